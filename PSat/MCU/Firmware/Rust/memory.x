@@ -4,3 +4,20 @@ MEMORY
   ROM : ORIGIN = 0x8000, LENGTH = 0x7F80
   VECTORS : ORIGIN = 0xFFA4, LENGTH = 0x5C
 }
+
+/* The flight log (see src/flight_log.rs) gets whatever ROM the image leaves free: from the end of .data's load image,
+   the last thing link.x puts in ROM, to the end of ROM. So the log shrinks as the code grows, rather than the two
+   overlapping. */
+_flight_log_start = ALIGN(LOADADDR(.data) + SIZEOF(.data), 2);
+_flight_log_end = ORIGIN(ROM) + LENGTH(ROM);
+/* 32 records of 20 bytes: the pre-launch ring, and as many again for the flight */
+ASSERT(_flight_log_end - _flight_log_start >= 0x280, "
+ERROR: less than 32 records of ROM are left for the flight log. Build with a lower max-level-* feature, or
+make the code smaller. See README.md.");
+
+SECTIONS
+{
+  /* Interned log strings, see src/ilog.rs. INFO sections aren't loaded, so these are only in the ELF, not in flash.
+     A string's address here is its ID. Starts at 1 so that no static is at the null address. */
+  .ilog 1 (INFO) : { KEEP(*(.ilog .ilog.*)) }
+}
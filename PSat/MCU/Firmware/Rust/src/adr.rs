@@ -0,0 +1,112 @@
+// Adaptive data rate. The ground station reports the SNR of each beacon back in an ACK, and the payload uses this
+// to pick the fastest, lowest power link profile that still leaves `config::ADR_TARGET_MARGIN_DB` of margin.
+//
+// A receiver can only hear packets sent with its own spreading factor, so the ground station has to change profile
+// at the same time as the payload. Each beacon announces the profile the payload wants to use next. The ground station
+// switches after it ACKs the beacon, and the payload switches once it hears that ACK.
+// If that ACK is lost, the payload can't tell whether the ground station switched. So after a missed ACK it swaps the
+// profile it sends with and the one it announces, and keeps alternating until an ACK arrives. Whichever profile the
+// ground station is on, it soon hears a beacon, and ACKing it puts both sides on the announced profile again.
+// If the two still get out of step they stop hearing eachother, so both fall back to the most robust profile.
+
+#![allow(dead_code)]
+use embedded_lora_rfm95::lora::types::SpreadingFactor;
+use crate::{config, config_store};
+
+pub struct LinkProfile {
+    pub spreading_factor: SpreadingFactor,
+    /// Output power in dBm. Must be between 2 and 17.
+    pub tx_power_dbm: u8,
+}
+
+/// Available profiles, ordered from most robust (slowest, most power hungry) to least.
+pub const PROFILES: [LinkProfile; 10] = [
+    LinkProfile { spreading_factor: SpreadingFactor::S12, tx_power_dbm: 17 },
+    LinkProfile { spreading_factor: SpreadingFactor::S11, tx_power_dbm: 17 },
+    LinkProfile { spreading_factor: SpreadingFactor::S10, tx_power_dbm: 17 },
+    LinkProfile { spreading_factor: SpreadingFactor::S9,  tx_power_dbm: 17 },
+    LinkProfile { spreading_factor: SpreadingFactor::S8,  tx_power_dbm: 17 },
+    LinkProfile { spreading_factor: SpreadingFactor::S7,  tx_power_dbm: 17 },
+    LinkProfile { spreading_factor: SpreadingFactor::S7,  tx_power_dbm: 14 },
+    LinkProfile { spreading_factor: SpreadingFactor::S7,  tx_power_dbm: 11 },
+    LinkProfile { spreading_factor: SpreadingFactor::S7,  tx_power_dbm: 8 },
+    LinkProfile { spreading_factor: SpreadingFactor::S7,  tx_power_dbm: 5 },
+];
+/// Used when ACKs stop arriving.
+pub const FALLBACK_PROFILE: u8 = 0;
+/// The default for `config_store::Config::link_profile`, the profile set up by `lora::new()`.
+pub const DEFAULT_PROFILE: u8 = 2;
+
+/// How far the margin has to stray from the target before we change profile. Stops us flip-flopping between two profiles.
+const HYSTERESIS_DB: i16 = 3;
+/// Consecutive missed ACKs before the payload falls back. The ground station uses the same number of missed beacons.
+pub const MAX_MISSED_ACKS: u8 = 3;
+
+/// The lowest SNR (in dB) at which each spreading factor can still be demodulated, from the SX1276 datasheet.
+fn required_snr_db(spreading_factor: SpreadingFactor) -> i16 {
+    match spreading_factor {
+        SpreadingFactor::S7  => -7,
+        SpreadingFactor::S8  => -10,
+        SpreadingFactor::S9  => -12,
+        SpreadingFactor::S10 => -15,
+        SpreadingFactor::S11 => -17,
+        SpreadingFactor::S12 => -20,
+    }
+}
+
+pub struct AdrController {
+    /// The profile both sides are currently using.
+    current: u8,
+    /// The profile announced in our beacons, which we'll switch to once an ACK confirms the ground station heard about it.
+    next: u8,
+    missed_acks: u8,
+}
+impl AdrController {
+    pub fn new() -> Self {
+        let profile = config_store::get().link_profile;
+        Self { current: profile, next: profile, missed_acks: 0 }
+    }
+
+    /// The profile to transmit with.
+    pub fn current(&self) -> u8 {
+        self.current
+    }
+
+    /// The profile to announce in the next beacon.
+    pub fn next(&self) -> u8 {
+        self.next
+    }
+
+    /// Call when the ground station ACKs a beacon. `snr` is the SNR it measured, in dB.
+    pub fn ack_received(&mut self, snr: i8) {
+        self.missed_acks = 0;
+
+        // The SNR was measured with the old profile. Work out what it will be with the new one.
+        let old = &PROFILES[self.current as usize];
+        let new = &PROFILES[self.next as usize];
+        let snr = snr as i16 + new.tx_power_dbm as i16 - old.tx_power_dbm as i16;
+        let margin = snr - required_snr_db(new.spreading_factor);
+        self.current = self.next;
+
+        let target = config::ADR_TARGET_MARGIN_DB as i16;
+        if margin > target + HYSTERESIS_DB && (self.current as usize) < PROFILES.len() - 1 {
+            self.next = self.current + 1;
+        }
+        else if margin < target - HYSTERESIS_DB && self.current > 0 {
+            self.next = self.current - 1;
+        }
+    }
+
+    /// Call when a beacon wasn't ACKed.
+    pub fn ack_missed(&mut self) {
+        self.missed_acks = self.missed_acks.saturating_add(1);
+        if self.missed_acks >= MAX_MISSED_ACKS {
+            self.current = FALLBACK_PROFILE;
+            self.next = FALLBACK_PROFILE;
+        }
+        else {
+            // The ground station may have already switched to `next`, see the top of this file
+            core::mem::swap(&mut self.current, &mut self.next);
+        }
+    }
+}
@@ -0,0 +1,55 @@
+// LoRa-APRS position reports, for payloads flying on the 70cm amateur band. These are picked up by the existing network
+// of LoRa-APRS iGates and end up on aprs.fi etc. without needing our own ground station.
+// Select with `config::BEACON_FORMAT`. Needs a licensed callsign in `config::APRS_CALLSIGN`, and a 433 MHz radio module.
+//
+// The packet encoding lives in `psat_link::aprs` so it can be tested on the host. This is the part that needs the radio,
+// GPS and config.
+
+#![allow(dead_code)]
+use arrayvec::ArrayVec;
+use embedded_lora_rfm95::{error::IoError, lora::types::{Bandwidth, CodingRate, CrcMode, Frequency, SpreadingFactor}};
+use psat_link::aprs::{encode_with, AprsError, Position, LORA_APRS_FREQ_HZ, MAX_PACKET_LEN};
+use crate::{config, gps::{GgaMessage, RmcMessage}, lora::Radio, protocol::Beacon};
+
+pub fn position_from_gga(gga: &GgaMessage) -> Position {
+    Position {
+        latitude_microdegrees: gga.latitude.as_microdegrees(),
+        longitude_microdegrees: gga.longitude.as_microdegrees(),
+        altitude_decimetres: Some(gga.altitude_msl.decimetres()),
+        course_speed: None,
+    }
+}
+
+pub fn position_from_rmc(rmc: &RmcMessage) -> Position {
+    Position {
+        latitude_microdegrees: rmc.latitude.as_microdegrees(),
+        longitude_microdegrees: rmc.longitude.as_microdegrees(),
+        altitude_decimetres: None,
+        course_speed: Some((rmc.course_tenths_degrees, rmc.speed_tenths_knots)),
+    }
+}
+
+pub fn position_from_beacon(beacon: &Beacon) -> Position {
+    Position {
+        latitude_microdegrees: beacon.latitude_microdegrees,
+        longitude_microdegrees: beacon.longitude_microdegrees,
+        altitude_decimetres: Some(beacon.altitude_decimetres),
+        course_speed: None,
+    }
+}
+
+/// Set the radio up to match the LoRa-APRS network: 433.775 MHz, SF12, 125 kHz, CR 4/5, CRC on.
+///
+/// The sync word must also be 0x12, which is the default `config::SYNC_WORD`.
+pub fn configure_radio(radio: &mut Radio) -> Result<(), IoError> {
+    radio.driver.set_frequency(Frequency::hz(LORA_APRS_FREQ_HZ))?;
+    radio.driver.set_bandwidth(Bandwidth::B125)?;
+    radio.driver.set_spreading_factor(SpreadingFactor::S12)?;
+    radio.driver.set_coding_rate(CodingRate::C4_5)?;
+    radio.driver.set_crc_mode(CrcMode::Enabled)
+}
+
+/// Build a complete LoRa-APRS packet using the callsign and comment from `config.rs`. Pass it straight to `Radio::transmit_start()`.
+pub fn encode(position: &Position) -> Result<ArrayVec<u8, MAX_PACKET_LEN>, AprsError> {
+    encode_with(config::APRS_CALLSIGN, config::APRS_SSID, config::APRS_PATH, config::APRS_COMMENT, position)
+}
@@ -0,0 +1,138 @@
+// Battery monitoring. The battery is connected to the ADC through a 1:2 divider (`Gpio::half_vbat`).
+//
+// Each reading is oversampled to average out ADC noise, corrected with the per-board calibration constants in `config.rs`,
+// and then low-pass filtered so that brief sags (e.g. while transmitting) don't trigger low battery events.
+
+#![allow(dead_code)]
+use embedded_hal::adc::OneShot;
+use msp430fr2x5x_hal::adc::Adc;
+use ufmt::derive::uDebug;
+use crate::{config_store, pin_mappings::HalfVbatPin};
+
+/// ADC reference, AVCC.
+const ADC_REF_MV: u32 = 3300;
+/// 10-bit ADC.
+const ADC_FULL_SCALE: u32 = 1024;
+/// ADC samples per reading.
+const OVERSAMPLES: u32 = 16;
+/// Each update moves the filtered voltage 1/2^FILTER_SHIFT of the way towards the new reading.
+const FILTER_SHIFT: u32 = 3;
+/// How far the voltage must rise back above a threshold before the level goes back up. Stops events chattering.
+pub const HYSTERESIS_MV: u16 = 50;
+
+/// Resting Li-ion cell voltage (mV) against state of charge (%). Interpolated linearly between points.
+const SOC_CURVE: [(u16, u8); 12] = [
+    (3300, 0),
+    (3600, 5),
+    (3690, 10),
+    (3730, 20),
+    (3770, 30),
+    (3800, 40),
+    (3840, 50),
+    (3870, 60),
+    (3950, 70),
+    (4020, 80),
+    (4110, 90),
+    (4200, 100),
+];
+
+#[derive(Debug, uDebug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum BatteryLevel {
+    /// Below `Config::battery_critical_mv`.
+    Critical,
+    /// Below `Config::battery_low_mv`.
+    Low,
+    Normal,
+}
+
+pub struct BatteryMonitor {
+    /// Filtered battery voltage in 1/16ths of a millivolt. `None` until the first reading.
+    filtered_mv_x16: Option<u32>,
+    level: BatteryLevel,
+}
+impl BatteryMonitor {
+    pub fn new() -> Self {
+        Self { filtered_mv_x16: None, level: BatteryLevel::Normal }
+    }
+
+    /// Take a new reading. Call this periodically, e.g. once a second.
+    ///
+    /// Returns the new level if the battery voltage crossed one of the thresholds.
+    pub fn update(&mut self, adc: &mut Adc, pin: &mut HalfVbatPin) -> Option<BatteryLevel> {
+        let sample_x16 = measure_mv(adc, pin) as u32 * 16;
+        let filtered = match self.filtered_mv_x16 {
+            None => sample_x16,
+            Some(old) if sample_x16 > old => old + ((sample_x16 - old) >> FILTER_SHIFT),
+            Some(old) => old - ((old - sample_x16) >> FILTER_SHIFT),
+        };
+        self.filtered_mv_x16 = Some(filtered);
+
+        let new_level = level_for(self.voltage_mv(), self.level);
+        if new_level != self.level {
+            self.level = new_level;
+            Some(new_level)
+        }
+        else {
+            None
+        }
+    }
+
+    /// Filtered battery voltage in millivolts. 0 before the first call to `update()`.
+    pub fn voltage_mv(&self) -> u16 {
+        self.filtered_mv_x16.map_or(0, |mv| (mv / 16) as u16)
+    }
+
+    /// Estimated state of charge, from 0 to 100%.
+    pub fn state_of_charge_percent(&self) -> u8 {
+        state_of_charge_percent(self.voltage_mv())
+    }
+
+    pub fn level(&self) -> BatteryLevel {
+        self.level
+    }
+}
+
+/// A single oversampled and calibrated battery voltage reading in millivolts. Blocks for the duration of the conversions.
+pub fn measure_mv(adc: &mut Adc, pin: &mut HalfVbatPin) -> u16 {
+    let mut sum: u32 = 0;
+    for _ in 0..OVERSAMPLES {
+        let Ok(count) = nb::block!(adc.read(pin));
+        sum += count as u32;
+    }
+    // Times two for the divider
+    let mv = sum * ADC_REF_MV * 2 / (ADC_FULL_SCALE * OVERSAMPLES);
+    let config = config_store::get();
+    let mv = (mv * config.battery_cal_gain_per_10000 as u32 / 10_000) as i32 + config.battery_cal_offset_mv as i32;
+    mv.clamp(0, u16::MAX as i32) as u16
+}
+
+/// Look up the state of charge of a resting Li-ion cell.
+pub fn state_of_charge_percent(mv: u16) -> u8 {
+    let (first_mv, first_soc) = SOC_CURVE[0];
+    if mv <= first_mv { return first_soc }
+
+    for pair in SOC_CURVE.windows(2) {
+        let ((lo_mv, lo_soc), (hi_mv, hi_soc)) = (pair[0], pair[1]);
+        if mv <= hi_mv {
+            let fraction = (mv - lo_mv) as u32 * (hi_soc - lo_soc) as u32 / (hi_mv - lo_mv) as u32;
+            return lo_soc + fraction as u8;
+        }
+    }
+    100
+}
+
+/// The level for a voltage, given the current level. Moving down is immediate, moving up needs `HYSTERESIS_MV` of margin.
+fn level_for(mv: u16, current: BatteryLevel) -> BatteryLevel {
+    let falling = level_with_margin(mv, 0);
+    let rising = level_with_margin(mv, HYSTERESIS_MV);
+    if falling < current { falling }
+    else if rising > current { rising }
+    else { current }
+}
+
+fn level_with_margin(mv: u16, margin_mv: u16) -> BatteryLevel {
+    let config = config_store::get();
+    if mv < config.battery_critical_mv + margin_mv { BatteryLevel::Critical }
+    else if mv < config.battery_low_mv + margin_mv { BatteryLevel::Low }
+    else { BatteryLevel::Normal }
+}
@@ -0,0 +1,187 @@
+// Blink codes on the RGB LED, for diagnosing problems when nothing is attached to the debug UART.
+//
+// Each code is a colour, blinked in one or more groups, then a pause before it repeats:
+//
+// | Code            | Colour  | Blinks                                          |
+// |-----------------|---------|-------------------------------------------------|
+// | Low battery     | Red     | 1                                               |
+// | Radio not found | Blue    | 2                                               |
+// | GPS silent      | Yellow  | 3                                               |
+// | Rail fault      | Magenta | 4                                               |
+// | Panic           | Red     | A long white flash, then the panic's line number, one group per digit (10 for 0) |
+//
+// `colour_at()` gives the colour at any point in the pattern, so a code can be shown without blocking. `show()` blinks
+// it once, blocking, for when nothing else is running (e.g. in the panic handler).
+
+use arrayvec::ArrayVec;
+use embedded_hal::blocking::delay::DelayMs;
+use msp430fr2355::{P2, PMM};
+use crate::board::Gpio;
+
+const BLINK_ON_MS: u16 = 250;
+const BLINK_OFF_MS: u16 = 250;
+const GROUP_GAP_MS: u16 = 750;
+const LEAD_IN_MS: u16 = 1000;
+const END_PAUSE_MS: u16 = 1500;
+/// Step size used by `show()`.
+const SHOW_STEP_MS: u16 = 50;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Colour {
+    Off,
+    Red,
+    Green,
+    Blue,
+    Yellow,
+    Cyan,
+    Magenta,
+    White,
+}
+impl Colour {
+    /// Which of the red, green and blue LEDs are on.
+    fn channels(self) -> (bool, bool, bool) {
+        match self {
+            Colour::Off => (false, false, false),
+            Colour::Red => (true, false, false),
+            Colour::Green => (false, true, false),
+            Colour::Blue => (false, false, true),
+            Colour::Yellow => (true, true, false),
+            Colour::Cyan => (false, true, true),
+            Colour::Magenta => (true, false, true),
+            Colour::White => (true, true, true),
+        }
+    }
+}
+
+/// Something that can show a `Colour`.
+pub trait RgbLed {
+    fn set_colour(&mut self, colour: Colour);
+}
+impl RgbLed for Gpio {
+    fn set_colour(&mut self, colour: Colour) {
+        let (red, green, blue) = colour.channels();
+        if red { self.red_led.turn_on() } else { self.red_led.turn_off() }
+        if green { self.green_led.turn_on() } else { self.green_led.turn_off() }
+        if blue { self.blue_led.turn_on() } else { self.blue_led.turn_off() }
+    }
+}
+
+/// Drives the LEDs through the port registers, for the panic handler, where `Board` isn't available.
+pub struct PanicLeds(());
+impl PanicLeds {
+    const MASK: u8 = 0b111; // P2.0 red, P2.1 blue, P2.2 green
+
+    /// Takes over the LED pins, whatever state they were left in.
+    pub fn steal() -> Self {
+        let p2 = unsafe { &*P2::ptr() };
+        p2.p2dir.modify(|r, w| unsafe { w.bits(r.bits() | Self::MASK) });
+        // In case we panicked before the GPIO was configured
+        unsafe { &*PMM::ptr() }.pm5ctl0.modify(|_, w| w.locklpm5().clear_bit());
+        Self(())
+    }
+}
+impl RgbLed for PanicLeds {
+    fn set_colour(&mut self, colour: Colour) {
+        let (red, green, blue) = colour.channels();
+        let on = red as u8 | (blue as u8) << 1 | (green as u8) << 2;
+        // Active low
+        unsafe { &*P2::ptr() }.p2out.modify(|r, w| unsafe { w.bits(r.bits() & !Self::MASK | !on & Self::MASK) });
+    }
+}
+
+/// A rough millisecond delay from counting cycles, for when there's no timer to hand. Assumes an 8MHz MCLK.
+pub struct SpinDelay;
+impl DelayMs<u16> for SpinDelay {
+    fn delay_ms(&mut self, ms: u16) {
+        const LOOPS_PER_MS: u16 = 2000; // About 4 cycles each
+        for _ in 0..ms {
+            for _ in 0..LOOPS_PER_MS { msp430::asm::nop(); }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlinkCode {
+    LowBattery,
+    RadioNotFound,
+    GpsSilent,
+    RailFault,
+    /// A panic, identified by its line number.
+    Panic { line: u32 },
+}
+impl BlinkCode {
+    pub fn colour(&self) -> Colour {
+        match self {
+            BlinkCode::LowBattery => Colour::Red,
+            BlinkCode::RadioNotFound => Colour::Blue,
+            BlinkCode::GpsSilent => Colour::Yellow,
+            BlinkCode::RailFault => Colour::Magenta,
+            BlinkCode::Panic { .. } => Colour::Red,
+        }
+    }
+
+    /// Number of blinks in each group.
+    fn groups(&self) -> ArrayVec<u8, 5> {
+        let number = match *self {
+            BlinkCode::LowBattery => 1,
+            BlinkCode::RadioNotFound => 2,
+            BlinkCode::GpsSilent => 3,
+            BlinkCode::RailFault => 4,
+            // Lines past 65535 aren't worth 32-bit arithmetic
+            BlinkCode::Panic { line } => line.min(u16::MAX as u32) as u16,
+        };
+        // A group per decimal digit, with 10 blinks for 0
+        let mut groups = ArrayVec::new();
+        let mut divisor = 1;
+        while number / divisor >= 10 { divisor *= 10; }
+        while divisor > 0 {
+            let digit = (number / divisor % 10) as u8;
+            let _ = groups.try_push(if digit == 0 { 10 } else { digit }); // Can't fail, a u16 has at most 5 digits
+            divisor /= 10;
+        }
+        groups
+    }
+
+    fn lead_in_ms(&self) -> u16 {
+        match self {
+            BlinkCode::Panic { .. } => LEAD_IN_MS + GROUP_GAP_MS,
+            _ => 0,
+        }
+    }
+
+    /// How long the whole pattern takes, including the pause at the end.
+    pub fn period_ms(&self) -> u16 {
+        let blinks: u16 = self.groups().iter().map(|&n| n as u16).sum();
+        let groups = self.groups().len() as u16;
+        self.lead_in_ms() + blinks * (BLINK_ON_MS + BLINK_OFF_MS) + (groups - 1) * GROUP_GAP_MS + END_PAUSE_MS
+    }
+
+    /// The colour to show `ms` into the pattern. Wraps around, so this can be given a free-running time.
+    pub fn colour_at(&self, ms: u32) -> Colour {
+        let mut t = (ms % self.period_ms() as u32) as u16;
+        if t < self.lead_in_ms() {
+            return if t < LEAD_IN_MS { Colour::White } else { Colour::Off };
+        }
+        t -= self.lead_in_ms();
+
+        for blinks in self.groups() {
+            let group_ms = blinks as u16 * (BLINK_ON_MS + BLINK_OFF_MS);
+            if t < group_ms {
+                return if t % (BLINK_ON_MS + BLINK_OFF_MS) < BLINK_ON_MS { self.colour() } else { Colour::Off };
+            }
+            t -= group_ms;
+            if t < GROUP_GAP_MS { return Colour::Off }
+            t -= GROUP_GAP_MS;
+        }
+        Colour::Off
+    }
+
+    /// Blink the code once, then turn the LED off. Blocks for `period_ms()`.
+    pub fn show(&self, leds: &mut impl RgbLed, delay: &mut impl DelayMs<u16>) {
+        for t in (0..self.period_ms()).step_by(SHOW_STEP_MS as usize) {
+            leds.set_colour(self.colour_at(t as u32));
+            delay.delay_ms(SHOW_STEP_MS);
+        }
+        leds.set_colour(Colour::Off);
+    }
+}
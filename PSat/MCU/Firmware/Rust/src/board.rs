@@ -13,7 +13,9 @@ use msp430fr2x5x_hal::{
 };
 use embedded_hal::digital::v2::{OutputPin, ToggleableOutputPin};
 use static_cell::StaticCell;
-use crate::{gps::Gps, lora::Radio, pin_mappings::*, println};
+use ufmt::derive::uDebug;
+use psat_link::hopping::HopSequence;
+use crate::{aprs, blink_code::BlinkCode, config::BeaconFormat, battery::{BatteryLevel, BatteryMonitor}, config, config_store, gps::Gps, lora::{Radio, RadioMode}, lpm::{self, SleepMode}, pin_mappings::*, power_rails::{PowerRails, Rail}, println, protocol::{Beacon, FrameKind, GROUND_STATION_ID}, reset_cause::ResetCause, watchdog::Watchdog, boot_log::BootLog, gps::GgaMessage, error, warn};
 
 /// Top-level object representing the board.
 /// 
@@ -26,11 +28,139 @@ pub struct Board {
     pub radio: Radio,
     pub gpio: Gpio,
     pub timer_b0: Timer<TB0>,
+    pub battery: BatteryMonitor,
+    pub rails: PowerRails,
+    pub watchdog: Watchdog,
+    /// Why we last reset, read at boot.
+    pub reset_cause: ResetCause,
+    /// Boot count and reset cause history, kept in FRAM.
+    pub boot_log: BootLog,
+    power_stage: PowerStage,
 }
 // This is where you should implement top-level functionality. 
 impl Board {
+    /// A single (unfiltered) battery voltage reading. For most purposes `update_battery()` is better.
     pub fn battery_voltage_mv(&mut self) -> u16 {
-        self.adc.read_voltage_mv(&mut self.gpio.half_vbat, 3300).unwrap() * 2
+        crate::battery::measure_mv(&mut self.adc, &mut self.gpio.half_vbat)
+    }
+
+    /// Take a new battery reading. Call this periodically, e.g. once a second.
+    /// 
+    /// Returns the new level if the battery crossed the low or critical threshold. See `battery.rs`.
+    pub fn update_battery(&mut self) -> Option<BatteryLevel> {
+        self.battery.update(&mut self.adc, &mut self.gpio.half_vbat)
+    }
+
+    /// A beacon reporting `gga`, with the rest of our telemetry filled in.
+    pub fn beacon(&self, gga: &GgaMessage, next_profile: u8) -> Beacon {
+        Beacon {
+            rail_faults: self.rails.packed_fault_counts(),
+            boot_count: self.boot_log.boot_count_u16(),
+            ..Beacon::from_gga(gga, next_profile)
+        }
+    }
+
+    pub fn power_stage(&self) -> PowerStage {
+        self.power_stage
+    }
+
+    /// Shed load as the battery runs down, so we keep beaconing for as long as possible instead of browning out over and over.
+    /// Call this after `update_battery()`.
+    /// 
+    /// Returns the new stage if it changed. Once this returns `PowerStage::DeepSleep`, call `low_battery_sleep()`.
+    /// Rails aren't switched back on if the battery recovers, as we don't know whether anything was using them.
+    pub fn apply_power_policy(&mut self) -> Option<PowerStage> {
+        let mv = self.battery.voltage_mv();
+        let falling = PowerStage::for_voltage(mv, 0);
+        let rising = PowerStage::for_voltage(mv, crate::battery::HYSTERESIS_MV);
+        let new_stage = if falling > self.power_stage { falling } 
+            else if rising < self.power_stage { rising } 
+            else { return None };
+        self.power_stage = new_stage;
+
+        if new_stage >= PowerStage::No5v {
+            self.rails.disable(Rail::V5).ok();
+        }
+        if new_stage >= PowerStage::No1v8 {
+            self.rails.disable(Rail::V1_8).ok();
+        }
+        if new_stage >= PowerStage::DeepSleep {
+            self.gpio.gps_en.set_high().ok(); // active low
+        }
+        Some(new_stage)
+    }
+
+    /// The final power saving stage. Sleeps in LPM3, waking every `Config::deep_sleep_beacon_interval_s` to send `last_position`
+    /// so the payload can still be found. Never returns, to avoid a flat battery cycling us in and out of sleep.
+    ///
+    /// Beacons are sent in `config::BEACON_FORMAT`, and hop along `hop_sequence` if `config::FREQUENCY_HOPPING` is set.
+    pub fn low_battery_sleep(&mut self, last_position: Option<Beacon>, hop_sequence: &mut HopSequence) -> ! {
+        self.gpio.gps_en.set_high().ok();
+        if config::ADAPTIVE_DATA_RATE {
+            // We won't be listening for ACKs, so use the profile the ground station falls back to
+            self.radio.set_profile(&crate::adr::PROFILES[crate::adr::FALLBACK_PROFILE as usize]).ok();
+        }
+        loop {
+            self.radio.set_mode(RadioMode::Sleep).ok();
+            self.sleep_s(config_store::get().deep_sleep_beacon_interval_s);
+
+            let Some(beacon) = &last_position else { continue };
+            // The FIFO can't be written in Sleep, and is cleared there
+            self.radio.set_mode(RadioMode::Standby).ok();
+            if config::FREQUENCY_HOPPING {
+                self.radio.set_frequency(hop_sequence.frequency_hz()).ok();
+                hop_sequence.advance();
+            }
+            let started = match config::BEACON_FORMAT {
+                BeaconFormat::Aprs => match aprs::encode(&aprs::position_from_beacon(beacon)) {
+                    Ok(packet) => self.radio.transmit_start(&packet).is_ok(),
+                    Err(_) => false,
+                },
+                BeaconFormat::Binary => self.radio.transmit_frame_start(GROUND_STATION_ID, FrameKind::Beacon, &beacon.to_bytes()).is_ok(),
+            };
+            if started {
+                let _ = nb::block!(self.radio.transmit_is_complete());
+            }
+        }
+    }
+
+    /// Sleep in LPM3 for `seconds`. Only ACLK keeps running, which Timer_B0 uses to wake us. See `lpm.rs`.
+    /// 
+    /// The watchdog keeps running too, so if it's been started we wake up to feed it as often as needed.
+    pub fn sleep_s(&mut self, seconds: u16) {
+        lpm::start_clock(&mut self.timer_b0);
+        let mut remaining_ms = seconds as u32 * 1000;
+        while remaining_ms > 0 {
+            let ms = remaining_ms.min(self.watchdog.feed_interval_ms().unwrap_or(u32::MAX));
+            lpm::sleep_ms(ms, SleepMode::Lpm3);
+            self.watchdog.feed();
+            remaining_ms -= ms;
+        }
+    }
+}
+
+/// Load shedding stages, entered in order as the battery runs down. Each stage includes the savings of the ones before it.
+#[derive(Debug, uDebug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PowerStage {
+    Normal,
+    /// The 5V rail is off.
+    No5v,
+    /// The 1.8V rail is off too.
+    No1v8,
+    /// Only every `config::REDUCED_BEACON_DIVISOR`th beacon slot is used.
+    ReducedBeacons,
+    /// GPS off, waking occasionally to send the last known position. See `Board::low_battery_sleep()`.
+    DeepSleep,
+}
+impl PowerStage {
+    /// The stage for a battery voltage, with each threshold raised by `margin_mv`.
+    fn for_voltage(mv: u16, margin_mv: u16) -> Self {
+        const STAGES: [PowerStage; 4] = [PowerStage::No5v, PowerStage::No1v8, PowerStage::ReducedBeacons, PowerStage::DeepSleep];
+        let mut stage = PowerStage::Normal;
+        for (next, threshold) in STAGES.iter().zip(config_store::get().power_stage_thresholds_mv) {
+            if mv < threshold.saturating_add(margin_mv) { stage = *next; }
+        }
+        stage
     }
 }
 
@@ -38,27 +168,42 @@ impl Board {
 // So we use the 'forward' functionality from embedded_hal_compat to automatically implement the v1.0 traits using the v0.2.7 version
 pub type FwSpiBus = Forward<SpiBus<E_USCI_B1>>;
 
+/// Times the radio-not-found blink code is shown before resetting.
+const RADIO_NOT_FOUND_BLINKS: u8 = 5;
+
 /// Call this function ONCE at the beginning of your program.
-/// Printing won't work until this function is called.
+/// Anything printed before this sets up the debug UART is held until it does.
 pub fn configure() -> Board {
-    // Take hardware registers and disable watchdog
+    // Take hardware registers and disable watchdog. Note why we reset before anything else can.
     let regs = msp430fr2355::Peripherals::take().unwrap();
-    let _wdt = Wdt::constrain(regs.WDT_A);
+    let mut wdt = Wdt::constrain(regs.WDT_A);
+    let reset_cause = ResetCause::read();
+    let boot_log = BootLog::record(reset_cause);
 
     // Configure GPIO. `used` are pins consumed by other peripherals.
-    let (gpio, used) = Gpio::configure(regs.P1, regs.P2, regs.P3, regs.P4, regs.P5, regs.P6, regs.PMM);
+    let (mut gpio, used) = Gpio::configure(regs.P1, regs.P2, regs.P3, regs.P4, regs.P5, regs.P6, regs.PMM);
     
     // Configure clocks to get accurate delay timing, and used by other peripherals
     let mut fram = Fram::new(regs.FRCTL);
-    let (smclk, aclk, delay) = ClockConfig::new(regs.CS)
+    let (smclk, aclk, mut delay) = ClockConfig::new(regs.CS)
         .mclk_dcoclk(DcoclkFreqSel::_8MHz, MclkDiv::_1)
         .smclk_on(SmclkDiv::_1)
         .aclk_refoclk() // 32768 Hz
         .freeze(&mut fram);
 
-    // Spare UART, useful for debug printing to a computer
-    crate::serial::configure_debug_serial(used.debug_tx_pin, &smclk, regs.E_USCI_A0);
+    // Watchdog, left halted until `Watchdog::start()`. ACLK keeps running in LPM3, unlike SMCLK.
+    wdt.set_aclk(&aclk);
+    let watchdog = Watchdog::new(wdt);
+
+    // Spare UART, useful for debug printing to a computer. With the `console` feature it also takes commands, see `console.rs`.
+    crate::serial::configure_debug_serial(used.debug_tx_pin, used.debug_rx_pin, &smclk, regs.E_USCI_A0);
     println!("Serial init"); // Like this!
+
+    // Per-payload settings, which the radio needs
+    match config_store::load() {
+        config_store::LoadResult::Loaded => (),
+        result => warn!("Config: {:?}", result),
+    }
     
     // SPI, used by the LoRa radio
     const SPI_FREQ_HZ: u32 = 250_000; // 250kHz is arbitrary
@@ -75,7 +220,17 @@ pub fn configure() -> Board {
     let spi_ref: &'static _ = SPI.init(RefCell::new(spi_bus.forward()));
     
     // LoRa radio
-    let radio = crate::lora::new(spi_ref, used.lora_cs, used.lora_reset, delay);
+    let radio = match crate::lora::new(spi_ref, used.lora_cs, used.lora_reset, delay) {
+        Ok(radio) => radio,
+        Err(_) => {
+            error!("Radio reports invalid silicon revision. Is the beacon connected?");
+            // Show the problem for a while, then reset and try again in case it was a loose connection
+            for _ in 0..RADIO_NOT_FOUND_BLINKS {
+                BlinkCode::RadioNotFound.show(&mut gpio, &mut delay);
+            }
+            crate::watchdog::reset_now();
+        },
+    };
 
     // GPS
     let gps = crate::gps::Gps::new(regs.E_USCI_A1, &smclk, used.gps_tx_pin, used.gps_rx_pin);
@@ -93,6 +248,13 @@ pub fn configure() -> Board {
         .use_smclk(&smclk, clk_div)
         .configure(used.i2c_scl_pin, used.i2c_sda_pin);
 
+    // PSU rails
+    let mut rails = PowerRails::new(used.enable_1v8, used.enable_5v, used.power_good_1v8, used.power_good_3v3);
+    // A rail that won't start is counted as a fault and left off. Keep going, as the MCU, radio and GPS don't need it.
+    if let Err((rail, e)) = rails.power_up(&mut delay) {
+        error!("{:?} rail failed to start: {:?}", rail, e);
+    }
+
     // ADC
     let adc = AdcConfig::new(
         ClockDivider::_1, 
@@ -103,7 +265,7 @@ pub fn configure() -> Board {
         .use_modclk()
         .configure(regs.ADC);
 
-    Board {delay, gps, radio, i2c, adc, gpio, timer_b0}
+    Board {delay, gps, radio, i2c, adc, gpio, timer_b0, battery: BatteryMonitor::new(), rails, watchdog, reset_cause, boot_log, power_stage: PowerStage::Normal}
 }
 
 /// The RGB LEDs are active low, which can be a little confusing. A helper struct to reduce cognitive load.
@@ -136,16 +298,9 @@ pub struct Gpio {
     pub gps_en:         GpsEnPin,
     pub half_vbat:      HalfVbatPin,
 
-    // PSU monitoring and control pins
-    pub power_good_1v8: PowerGood1v8Pin,
-    pub power_good_3v3: PowerGood3v3Pin,
-    pub enable_1v8:     Enable1v8Pin,
-    pub enable_5v:      Enable5vPin,
-
     // Unused UCA0 pins
     pub pin1_4: Pin<P1, Pin4, Input<Floating>>,
     pub pin1_5: Pin<P1, Pin5, Input<Floating>>,
-    pub pin1_6: Pin<P1, Pin6, Input<Floating>>,
 
     // Unused UCA1 pins
     pub pin4_0: Pin<P4, Pin0, Input<Floating>>,
@@ -215,18 +370,16 @@ impl Gpio {
         gps_en.set_low().ok();
 
         let debug_tx_pin = port1.pin7.to_alternate1();
+        let debug_rx_pin = port1.pin6.to_alternate1();
 
         let i2c_sda_pin = port1.pin2.to_alternate1();
         let i2c_scl_pin = port1.pin3.to_alternate1();
 
-        // Pins consumed by other perihperals
-        let used = ConsumedPins {mosi, miso, sclk, lora_cs, lora_reset, gps_rx_pin, gps_tx_pin, debug_tx_pin, i2c_scl_pin, i2c_sda_pin};
 
         let pin1_0 = port1.pin0;
         let pin1_1 = port1.pin1;
         let pin1_4 = port1.pin4;
         let pin1_5 = port1.pin5;
-        let pin1_6 = port1.pin6;
 
         let pin2_3 = port2.pin3;
         let pin2_4 = port2.pin4;
@@ -234,12 +387,16 @@ impl Gpio {
         let pin2_6 = port2.pin6;
         let pin2_7 = port2.pin7;
 
+        // PSU monitoring and control. See `PowerRails`.
         let power_good_1v8 = port3.pin0.pullup();
         let power_good_3v3 = port3.pin1.pullup();
-        let mut enable_1v8 = port3.pin2.to_output();
-        enable_1v8.set_low().ok();
-        let mut enable_5v = port3.pin3.to_output();
-        enable_5v.set_low().ok();
+        let enable_1v8 = port3.pin2.to_output();
+        let enable_5v = port3.pin3.to_output();
+
+        // Pins consumed by other perihperals
+        let used = ConsumedPins {mosi, miso, sclk, lora_cs, lora_reset, gps_rx_pin, gps_tx_pin, debug_tx_pin, debug_rx_pin, i2c_scl_pin, i2c_sda_pin,
+            power_good_1v8, power_good_3v3, enable_1v8, enable_5v};
+
         let pin3_4 = port3.pin4;
         let pin3_5 = port3.pin5;
         let pin3_6 = port3.pin6;
@@ -263,9 +420,7 @@ impl Gpio {
             lora_irq, 
             gps_en, 
             half_vbat, 
-            power_good_1v8, power_good_3v3, 
-            enable_1v8, enable_5v,
-            pin1_0, pin1_1, pin1_4, pin1_5, pin1_6,
+            pin1_0, pin1_1, pin1_4, pin1_5,
             pin2_3, pin2_4, pin2_5, pin2_6, pin2_7,
             pin3_4, pin3_5, pin3_6, pin3_7,
             pin4_0,
@@ -287,6 +442,11 @@ struct ConsumedPins {
     gps_tx_pin:     GpsTxPin,
     gps_rx_pin:     GpsRxPin,
     debug_tx_pin:   DebugTxPin,
+    debug_rx_pin:   DebugRxPin,
     i2c_sda_pin:    I2cSdaPin,
     i2c_scl_pin:    I2cSclPin,
+    power_good_1v8: PowerGood1v8Pin,
+    power_good_3v3: PowerGood3v3Pin,
+    enable_1v8:     Enable1v8Pin,
+    enable_5v:      Enable5vPin,
 }
\ No newline at end of file
@@ -0,0 +1,66 @@
+// A boot counter and the causes of the last few resets, kept in information FRAM so we can tell after a flight whether
+// (and why) the payload rebooted. The boot count is also sent in every beacon.
+
+use crate::{info_fram, print, println, reset_cause::ResetCause};
+
+/// How many reset causes to keep.
+pub const HISTORY_LEN: usize = 8;
+/// Marks a valid record. Change it if the layout of `Stored` changes, so an old record is discarded rather than misread.
+const MAGIC: u16 = 0xB007;
+
+/// The record as stored in FRAM.
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct Stored {
+    magic: u16,
+    boot_count: u32,
+    /// SYSRSTIV values, newest first. 0 for none.
+    causes: [u8; HISTORY_LEN],
+}
+
+pub struct BootLog {
+    /// Number of times we've booted, including this one.
+    pub boot_count: u32,
+    causes: [u8; HISTORY_LEN],
+}
+impl BootLog {
+    /// Count this boot and add its reset cause to the history. Call this once, early in boot.
+    pub fn record(cause: ResetCause) -> Self {
+        let stored: Stored = unsafe { info_fram::read(info_fram::BOOT_LOG) };
+        let (boot_count, mut causes) = match stored.magic {
+            MAGIC => (stored.boot_count, stored.causes),
+            _ => (0, [0; HISTORY_LEN]), // First boot, or the FRAM was erased
+        };
+
+        causes.copy_within(0..HISTORY_LEN - 1, 1);
+        causes[0] = cause.vector();
+        let boot_count = boot_count.saturating_add(1);
+        info_fram::write(info_fram::BOOT_LOG, &Stored { magic: MAGIC, boot_count, causes });
+
+        Self { boot_count, causes }
+    }
+
+    /// The boot count for telemetry. Saturates at `u16::MAX`.
+    pub fn boot_count_u16(&self) -> u16 {
+        self.boot_count.min(u16::MAX as u32) as u16
+    }
+
+    /// Reset causes, newest (i.e. this boot's) first.
+    pub fn causes(&self) -> impl Iterator<Item = ResetCause> + '_ {
+        self.causes.iter().take_while(|&&c| c != 0).map(|&c| ResetCause::from_vector(c as u16))
+    }
+
+    pub fn print(&self) {
+        print!("Boot #{}, reset causes (newest first):", self.boot_count);
+        for cause in self.causes() {
+            print!(" {:?}", cause);
+        }
+        println!("");
+    }
+}
+
+/// The current boot count, straight from FRAM. For when there's no `BootLog` to hand, e.g. in the panic handler.
+pub fn stored_boot_count() -> u32 {
+    let stored: Stored = unsafe { info_fram::read(info_fram::BOOT_LOG) };
+    if stored.magic == MAGIC { stored.boot_count } else { 0 }
+}
@@ -0,0 +1,102 @@
+// Per-payload settings. Change these when building firmware for a specific payload.
+//
+// Settings in `config_store::Config` are only defaults: a payload uses whatever is stored in its FRAM, so one binary can
+// be configured for each payload. Use `config_store::get()` for those, not the constants here.
+
+#![allow(dead_code)]
+use msp430fr2x5x_hal::watchdog::WdtClkPeriods;
+use crate::{serial::OverflowPolicy, status_led::StatusLedMode, tdma::SlotSchedule};
+
+/// Centre frequency of the radio, unless hopping.
+pub const LORA_FREQ_HZ: u32 = 915_000_000;
+
+/// This payload's radio address. Must be unique among payloads sharing a channel.
+/// Must not be `protocol::GROUND_STATION_ID` (0x00) or `protocol::BROADCAST_ID` (0xFF).
+pub const PAYLOAD_ID: u8 = 0x01;
+
+/// LoRa sync word. Radios only hear packets with a matching sync word. 0x12 is the common 'private network' value, avoid 0x34 (LoRaWAN).
+pub const SYNC_WORD: u8 = 0x12;
+
+/// Beacon slot allocation. Set to `None` to beacon after every GPS fix (fine if you're the only payload on the channel).
+///
+/// 4 slots of 2 seconds each means every payload beacons once every 8 seconds.
+pub const TDMA_SCHEDULE: Option<SlotSchedule> = Some(SlotSchedule { num_slots: 4, slot_len_ms: 2000, guard_ms: 900 });
+
+/// Hop to a new channel after every beacon instead of always using the configured frequency. See `psat_link::hopping`.
+/// The ground station must be started with `ground_station::run_hopping()` to follow.
+pub const FREQUENCY_HOPPING: bool = false;
+
+/// Adjust spreading factor and transmit power based on how well the ground station hears us. See `adr.rs`.
+/// Only use this with a single payload per ground station, as the ground station has to follow the payload's profile.
+pub const ADAPTIVE_DATA_RATE: bool = false;
+// Adaptive data rate falls back to SF12, which stays on a channel far longer than hopping allows. See `psat_link::hopping`.
+const _: () = assert!(!(FREQUENCY_HOPPING && ADAPTIVE_DATA_RATE), "FREQUENCY_HOPPING can't be used with ADAPTIVE_DATA_RATE");
+
+/// The SNR above the demodulation limit that adaptive data rate aims to keep, in dB. Larger values are more robust to fading.
+pub const ADR_TARGET_MARGIN_DB: i8 = 10;
+
+/// Listen for other payloads' beacons and re-broadcast them, so the ground station can find payloads it can't hear directly.
+/// Costs power, as the radio is listening most of the time. Not compatible with `FREQUENCY_HOPPING`. See `relay.rs`.
+pub const RELAY_MODE: bool = false;
+
+/// Which packet format beacons are sent in.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum BeaconFormat {
+    /// Our own compact binary frame, see `protocol.rs`. Needs our ground station.
+    Binary,
+    /// LoRa-APRS position reports on 433.775 MHz, heard by any LoRa-APRS iGate. Amateur licence required, see `aprs.rs`.
+    /// Adaptive data rate, hopping and relaying only work with `Binary`.
+    Aprs,
+}
+pub const BEACON_FORMAT: BeaconFormat = BeaconFormat::Binary;
+// LoRa-APRS iGates listen on a single frequency
+const _: () = assert!(!(matches!(BEACON_FORMAT, BeaconFormat::Aprs) && FREQUENCY_HOPPING), "BeaconFormat::Aprs can't be used with FREQUENCY_HOPPING");
+
+/// Your licensed callsign, without SSID. Only used with `BeaconFormat::Aprs`.
+pub const APRS_CALLSIGN: &str = "N0CALL";
+/// 11 is the conventional SSID for balloons.
+pub const APRS_SSID: u8 = 11;
+/// Digipeater path. Leave empty at altitude, a balloon is already heard by every digipeater for hundreds of km.
+pub const APRS_PATH: &str = "";
+/// Free text appended to each position report. Keep it short, every byte costs airtime at SF12.
+pub const APRS_COMMENT: &str = "PSat";
+
+/// Battery voltage calibration, measured for each board: `actual = reading * GAIN / 10000 + OFFSET`.
+/// Measure the battery with a multimeter at two voltages and compare against `battery::measure_mv()`.
+/// Corrects for the tolerance of the 3.3V rail (the ADC reference) and of the divider resistors.
+pub const BATTERY_CAL_GAIN_PER_10000: u16 = 10_000;
+pub const BATTERY_CAL_OFFSET_MV: i16 = 0;
+
+/// Battery voltages below which `battery::BatteryMonitor` reports the battery as low or critical.
+pub const BATTERY_LOW_MV: u16 = 3600;
+pub const BATTERY_CRITICAL_MV: u16 = 3400;
+
+/// Battery voltages at which `Board::apply_power_policy()` switches off the 5V rail, switches off the 1.8V rail, reduces the
+/// beacon rate, and finally turns off the GPS and sleeps. Must be in descending order.
+pub const POWER_STAGE_THRESHOLDS_MV: [u16; 4] = [3550, 3450, 3350, 3250];
+/// With a low battery, only beacon in one out of this many slots.
+pub const REDUCED_BEACON_DIVISOR: u8 = 4;
+/// Time between last known position beacons once the battery is nearly flat.
+pub const DEEP_SLEEP_BEACON_INTERVAL_S: u16 = 300;
+
+/// Print the time spent asleep and an estimate of the MCU's average current every 10 seconds. See `lpm.rs`.
+pub const CURRENT_MEASUREMENT_MODE: bool = false;
+
+/// What `println!` does when it gets ahead of the debug UART, see `serial.rs`. `Block` never loses output (long dumps like
+/// `log dump` need it) and lets interrupts in while it waits, `Drop` never holds up the payload.
+pub const DEBUG_SERIAL_OVERFLOW: OverflowPolicy = OverflowPolicy::Block;
+
+/// The payload resets if it stops making progress for this many cycles of ACLK (32768 Hz). 512K is 16 seconds, which
+/// leaves room for the longest blocking operation: a beacon at SF12 and waiting for its ACK. See `watchdog.rs`.
+pub const WATCHDOG_PERIOD: WdtClkPeriods = WdtClkPeriods::_512K;
+
+/// What the RGB LED shows, see `status_led.rs`. Use `FaultsOnly` or `Off` in flight to save power.
+pub const STATUS_LED_MODE: StatusLedMode = StatusLedMode::FaultsOnly;
+
+/// Time between flight log records, see `flight_log.rs`. The log gets whatever flash the code leaves free, which is about
+/// 60 records in a default build, so 3 minutes covers a little over 2 hours of flight.
+pub const FLIGHT_LOG_INTERVAL_S: u16 = 180;
+/// Records kept from before launch, to show what happened on the pad.
+pub const PRE_TRIGGER_RECORDS: u16 = 16;
+/// Launch is detected when the altitude climbs this far above the lowest altitude seen since boot.
+pub const LAUNCH_ALTITUDE_GAIN_M: u16 = 100;
@@ -0,0 +1,307 @@
+// Settings that can differ between payloads running the same firmware, kept in information FRAM.
+//
+// The constants in `config.rs` are only the defaults. At boot, `load()` reads the stored `Config` and checks its CRC.
+// If nothing valid is stored (a new board, or the FRAM was corrupted) the defaults are used instead. Call `get()` for
+// the settings in use, and `modify()` then `commit()` to change them.
+//
+// The stored record carries the version of its layout. Fields are only ever added to the end of `Config` (bump `VERSION`
+// when you do), so firmware can always read another version's record: it takes the fields both versions know about, and
+// uses defaults for the rest. If a field's meaning ever changes, convert it in `migrate()`.
+
+use core::{cell::Cell, mem::size_of};
+use msp430::{critical_section, interrupt::Mutex};
+use ufmt::derive::uDebug;
+use psat_link::hopping;
+use crate::{adr, config, info_fram, protocol::{BROADCAST_ID, GROUND_STATION_ID}, tdma::SlotSchedule};
+#[cfg(feature = "console")]
+use crate::println;
+
+/// Marks a stored config. The CRC catches anything else.
+const MAGIC: u16 = 0xC0F6;
+/// Layout version of `Config`.
+pub const VERSION: u16 = 1;
+
+/// Frequencies the SX1276 family can tune to.
+const FREQUENCY_RANGE_HZ: core::ops::RangeInclusive<u32> = 137_000_000..=1_020_000_000;
+/// Battery thresholds outside this are a typo: a single Li-ion cell never gets near either end.
+const BATTERY_THRESHOLD_RANGE_MV: core::ops::RangeInclusive<u16> = 2_500..=5_000;
+
+static ACTIVE: Mutex<Cell<Config>> = Mutex::new(Cell::new(Config::DEFAULT));
+
+/// Everything is a plain integer, so whatever is in FRAM is at least a valid `Config`. `validate()` checks the values.
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct Config {
+    /// Default `config::LORA_FREQ_HZ`.
+    pub frequency_hz: u32,
+    /// See `tdma::SlotSchedule`.
+    pub tdma_slot_len_ms: u16,
+    pub tdma_guard_ms: u16,
+    pub battery_low_mv: u16,
+    pub battery_critical_mv: u16,
+    pub power_stage_thresholds_mv: [u16; 4],
+    pub battery_cal_gain_per_10000: u16,
+    pub battery_cal_offset_mv: i16,
+    pub deep_sleep_beacon_interval_s: u16,
+    pub flight_log_interval_s: u16,
+    pub launch_altitude_gain_m: u16,
+    pub payload_id: u8,
+    pub sync_word: u8,
+    /// Index into `adr::PROFILES` set up at boot. With adaptive data rate, must match the ground station's.
+    pub link_profile: u8,
+    /// 0 beacons after every fix rather than using TDMA.
+    pub tdma_slots: u8,
+}
+impl Config {
+    pub const DEFAULT: Self = {
+        let (tdma_slots, tdma_slot_len_ms, tdma_guard_ms) = match config::TDMA_SCHEDULE {
+            Some(s) => (s.num_slots, s.slot_len_ms as u16, s.guard_ms as u16),
+            None => (0, 0, 0),
+        };
+        Self {
+            frequency_hz: config::LORA_FREQ_HZ,
+            tdma_slot_len_ms,
+            tdma_guard_ms,
+            battery_low_mv: config::BATTERY_LOW_MV,
+            battery_critical_mv: config::BATTERY_CRITICAL_MV,
+            power_stage_thresholds_mv: config::POWER_STAGE_THRESHOLDS_MV,
+            battery_cal_gain_per_10000: config::BATTERY_CAL_GAIN_PER_10000,
+            battery_cal_offset_mv: config::BATTERY_CAL_OFFSET_MV,
+            deep_sleep_beacon_interval_s: config::DEEP_SLEEP_BEACON_INTERVAL_S,
+            flight_log_interval_s: config::FLIGHT_LOG_INTERVAL_S,
+            launch_altitude_gain_m: config::LAUNCH_ALTITUDE_GAIN_M,
+            payload_id: config::PAYLOAD_ID,
+            sync_word: config::SYNC_WORD,
+            link_profile: if config::FREQUENCY_HOPPING { hopping::SLOWEST_PROFILE } else { adr::DEFAULT_PROFILE },
+            tdma_slots,
+        }
+    };
+
+    /// `None` if TDMA is off.
+    pub fn tdma_schedule(&self) -> Option<SlotSchedule> {
+        if self.tdma_slots == 0 { return None }
+        Some(SlotSchedule { num_slots: self.tdma_slots, slot_len_ms: self.tdma_slot_len_ms as u32, guard_ms: self.tdma_guard_ms as u32 })
+    }
+
+    /// Time between beacons. The ground station uses this to predict when a hopping payload will next transmit.
+    pub fn beacon_interval_ms(&self) -> u32 {
+        match self.tdma_schedule() {
+            Some(schedule) => schedule.cycle_len_ms(),
+            None => 1000, // Once per GPS fix
+        }
+    }
+
+    /// Check that the settings make sense together.
+    pub fn validate(&self) -> Result<(), InvalidConfig> {
+        use InvalidConfig::*;
+        if self.payload_id == GROUND_STATION_ID || self.payload_id == BROADCAST_ID { return Err(PayloadId) }
+        if !FREQUENCY_RANGE_HZ.contains(&self.frequency_hz) { return Err(Frequency) }
+        if self.link_profile as usize >= adr::PROFILES.len() { return Err(LinkProfile) }
+        if config::FREQUENCY_HOPPING && self.link_profile < hopping::SLOWEST_PROFILE { return Err(LinkProfile) }
+        if self.tdma_slots != 0 && self.tdma_guard_ms >= self.tdma_slot_len_ms { return Err(Tdma) }
+        if self.battery_critical_mv >= self.battery_low_mv { return Err(BatteryThresholds) }
+        if self.power_stage_thresholds_mv.windows(2).any(|pair| pair[0] <= pair[1]) { return Err(BatteryThresholds) }
+        let mut thresholds = [self.battery_low_mv, self.battery_critical_mv].into_iter().chain(self.power_stage_thresholds_mv);
+        if thresholds.any(|mv| !BATTERY_THRESHOLD_RANGE_MV.contains(&mv)) { return Err(BatteryThresholds) }
+        if self.flight_log_interval_s == 0 || self.deep_sleep_beacon_interval_s == 0 { return Err(Interval) }
+        Ok(())
+    }
+
+    /// The value of the setting called `name` (as listed in `FIELD_NAMES`), if there is one.
+    #[cfg(feature = "console")]
+    pub fn field(&self, name: &str) -> Option<i32> {
+        let [stage_1, stage_2, stage_3, stage_4] = self.power_stage_thresholds_mv;
+        Some(match name {
+            "payload_id" => self.payload_id as i32,
+            "frequency_hz" => self.frequency_hz as i32,
+            "sync_word" => self.sync_word as i32,
+            "link_profile" => self.link_profile as i32,
+            "tdma_slots" => self.tdma_slots as i32,
+            "tdma_slot_len_ms" => self.tdma_slot_len_ms as i32,
+            "tdma_guard_ms" => self.tdma_guard_ms as i32,
+            "battery_low_mv" => self.battery_low_mv as i32,
+            "battery_critical_mv" => self.battery_critical_mv as i32,
+            "power_stage_1_mv" => stage_1 as i32,
+            "power_stage_2_mv" => stage_2 as i32,
+            "power_stage_3_mv" => stage_3 as i32,
+            "power_stage_4_mv" => stage_4 as i32,
+            "battery_cal_gain_per_10000" => self.battery_cal_gain_per_10000 as i32,
+            "battery_cal_offset_mv" => self.battery_cal_offset_mv as i32,
+            "deep_sleep_beacon_interval_s" => self.deep_sleep_beacon_interval_s as i32,
+            "flight_log_interval_s" => self.flight_log_interval_s as i32,
+            "launch_altitude_gain_m" => self.launch_altitude_gain_m as i32,
+            _ => return None,
+        })
+    }
+
+    /// Change the setting called `name`. Doesn't check that the result is valid, see `validate()`.
+    #[cfg(feature = "console")]
+    pub fn set_field(&mut self, name: &str, value: i32) -> Result<(), FieldError> {
+        fn fit<T: TryFrom<i32>>(value: i32) -> Result<T, FieldError> {
+            T::try_from(value).map_err(|_| FieldError::OutOfRange)
+        }
+        let stages = &mut self.power_stage_thresholds_mv;
+        match name {
+            "payload_id" => self.payload_id = fit(value)?,
+            "frequency_hz" => self.frequency_hz = fit(value)?,
+            "sync_word" => self.sync_word = fit(value)?,
+            "link_profile" => self.link_profile = fit(value)?,
+            "tdma_slots" => self.tdma_slots = fit(value)?,
+            "tdma_slot_len_ms" => self.tdma_slot_len_ms = fit(value)?,
+            "tdma_guard_ms" => self.tdma_guard_ms = fit(value)?,
+            "battery_low_mv" => self.battery_low_mv = fit(value)?,
+            "battery_critical_mv" => self.battery_critical_mv = fit(value)?,
+            "power_stage_1_mv" => stages[0] = fit(value)?,
+            "power_stage_2_mv" => stages[1] = fit(value)?,
+            "power_stage_3_mv" => stages[2] = fit(value)?,
+            "power_stage_4_mv" => stages[3] = fit(value)?,
+            "battery_cal_gain_per_10000" => self.battery_cal_gain_per_10000 = fit(value)?,
+            "battery_cal_offset_mv" => self.battery_cal_offset_mv = fit(value)?,
+            "deep_sleep_beacon_interval_s" => self.deep_sleep_beacon_interval_s = fit(value)?,
+            "flight_log_interval_s" => self.flight_log_interval_s = fit(value)?,
+            "launch_altitude_gain_m" => self.launch_altitude_gain_m = fit(value)?,
+            _ => return Err(FieldError::UnknownField),
+        }
+        Ok(())
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self as *const Self as *const u8, size_of::<Self>()) }
+    }
+}
+
+/// Names for `Config::field()` and `Config::set_field()`.
+#[cfg(feature = "console")]
+pub const FIELD_NAMES: [&str; 18] = [
+    "payload_id", "frequency_hz", "sync_word", "link_profile", "tdma_slots", "tdma_slot_len_ms", "tdma_guard_ms",
+    "battery_low_mv", "battery_critical_mv", "power_stage_1_mv", "power_stage_2_mv", "power_stage_3_mv", "power_stage_4_mv",
+    "battery_cal_gain_per_10000", "battery_cal_offset_mv", "deep_sleep_beacon_interval_s", "flight_log_interval_s",
+    "launch_altitude_gain_m",
+];
+
+#[cfg(feature = "console")]
+#[derive(Debug, uDebug, Clone, Copy, PartialEq, Eq)]
+pub enum FieldError {
+    UnknownField,
+    /// Doesn't fit in the field's type.
+    OutOfRange,
+}
+
+/// Why `validate()` rejected a config.
+#[derive(Debug, uDebug, Clone, Copy, PartialEq, Eq)]
+pub enum InvalidConfig {
+    /// Can't be the ground station's or the broadcast address.
+    PayloadId,
+    Frequency,
+    LinkProfile,
+    /// The guard time must be shorter than a slot.
+    Tdma,
+    /// Low must be above critical, the power stage thresholds must be in descending order, and all must be 2.5-5V.
+    BatteryThresholds,
+    Interval,
+}
+
+/// Where the settings in use came from.
+#[derive(Debug, uDebug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadResult {
+    Loaded,
+    /// Stored by firmware with a different `VERSION`. Settings it didn't have are defaults.
+    Migrated { from_version: u16 },
+    /// Nothing stored yet, so using defaults.
+    Blank,
+    /// The CRC didn't match, so using defaults.
+    Corrupt,
+    /// Stored, but failed `validate()`, so using defaults.
+    Invalid(InvalidConfig),
+}
+
+/// The record as stored in FRAM.
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct Stored {
+    magic: u16,
+    version: u16,
+    /// Bytes of `config` that were written, i.e. `size_of::<Config>()` in that version.
+    len: u16,
+    /// CRC of those bytes.
+    crc: u16,
+    config: Config,
+}
+
+/// Read the stored config and start using it. Call this once, early in boot.
+pub fn load() -> LoadResult {
+    let stored: Stored = unsafe { info_fram::read(info_fram::CONFIG) };
+    let len = (stored.len as usize).min(size_of::<Config>());
+    if stored.magic != MAGIC { return LoadResult::Blank }
+    if crc16(&stored.config.as_bytes()[..len]) != stored.crc { return LoadResult::Corrupt }
+
+    let config = if stored.version == VERSION { stored.config } else { migrate(&stored.config, len, stored.version) };
+    if let Err(e) = config.validate() { return LoadResult::Invalid(e) }
+
+    critical_section::with(|cs| ACTIVE.borrow(cs).set(config));
+    if stored.version == VERSION { LoadResult::Loaded } else { LoadResult::Migrated { from_version: stored.version } }
+}
+
+/// The settings in use.
+pub fn get() -> Config {
+    critical_section::with(|cs| ACTIVE.borrow(cs).get())
+}
+
+/// Change the settings in use. Some (such as the radio's) are only applied at boot, so `commit()` and reset.
+/// Nothing is saved until `commit()`.
+#[cfg(feature = "console")]
+pub fn modify(f: impl FnOnce(&mut Config)) {
+    critical_section::with(|cs| {
+        let active = ACTIVE.borrow(cs);
+        let mut config = active.get();
+        f(&mut config);
+        active.set(config);
+    });
+}
+
+/// Go back to the defaults from `config.rs`. Nothing is saved until `commit()`.
+#[cfg(feature = "console")]
+pub fn reset_to_defaults() {
+    critical_section::with(|cs| ACTIVE.borrow(cs).set(Config::DEFAULT));
+}
+
+/// Save the settings in use, so they're loaded on every boot from now on.
+#[cfg(feature = "console")]
+pub fn commit() -> Result<(), InvalidConfig> {
+    let config = get();
+    config.validate()?;
+    let stored = Stored { magic: MAGIC, version: VERSION, len: size_of::<Config>() as u16, crc: crc16(config.as_bytes()), config };
+    info_fram::write(info_fram::CONFIG, &stored);
+    Ok(())
+}
+
+/// Print the settings in use.
+#[cfg(feature = "console")]
+pub fn print() {
+    let config = get();
+    for name in FIELD_NAMES {
+        if let Some(value) = config.field(name) {
+            println!("{} = {}", name, value);
+        }
+    }
+}
+
+/// Build a current `Config` from one stored by another version, whose first `len` bytes are valid.
+fn migrate(old: &Config, len: usize, _from_version: u16) -> Config {
+    let mut config = Config::DEFAULT;
+    // Fields are only ever added at the end, so the ones both versions have are in the same place
+    unsafe { core::ptr::copy_nonoverlapping(old as *const Config as *const u8, &mut config as *mut Config as *mut u8, len) };
+    config
+}
+
+/// CRC-16/CCITT-FALSE. Bitwise, as a table would cost 512 bytes of flash to check 40 bytes once per boot.
+fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+    for &byte in bytes {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
@@ -0,0 +1,290 @@
+// A command line on the debug UART, for checking and configuring a payload on the bench. Connect a terminal at 115200
+// baud and type `help`. Only built with the `console` cargo feature, as it takes about a third of the flash.
+//
+// Characters are echoed as they're typed. Backspace deletes, Ctrl-C throws the line away and Enter runs it. There's no
+// tab completion or history, to keep it small. Lines are split on spaces into a command and its arguments:
+//
+// | Command                        | Does                                                         |
+// |--------------------------------|--------------------------------------------------------------|
+// | `help`                         | Lists the commands                                           |
+// | `status`                       | Boot count, battery, power stage, rails and flight log       |
+// | `gps`                          | The last GPS fix                                             |
+// | `radio tx <text>`              | Sends `text` as a raw packet                                 |
+// | `radio rx [seconds]`           | Prints the next packet received                              |
+// | `rails [1v8/5v] [on/off]`      | Prints or switches the PSU rails                             |
+// | `config [get [name]]`          | Prints the settings, see `config_store.rs`                   |
+// | `config set <name> <value>`    | Changes a setting until reset                                |
+// | `config commit`/`defaults`     | Saves the settings to FRAM, or goes back to the defaults     |
+// | `log dump`/`clear`             | Prints the flight log as CSV, or clears it                   |
+// | `loglevel [level/off]`         | Prints or sets the runtime log level, see `log.rs`           |
+// | `reboot`                       | Resets the MCU                                               |
+//
+// Commands run from a scheduler task, so anything slow (like `radio rx`) holds up the rest of the payload while it runs.
+
+use core::{str::FromStr, time::Duration};
+use arrayvec::{ArrayString, ArrayVec};
+use embedded_lora_rfm95::error::RxCompleteError;
+use crate::{board::Board, config, config_store, flight_log::FlightLog, log, lora, lpm, power_rails::Rail, print, println, protocol::Beacon, serial, watchdog};
+
+/// Longest line that can be typed. Anything after this is ignored.
+const MAX_LINE_LEN: usize = 64;
+/// Most words in a line, including the command.
+const MAX_ARGS: usize = 8;
+/// The watchdog resets us after 16 seconds, so don't listen for too long. At low spreading factors the radio can't
+/// listen this long anyway, see `Radio::recieve_start()`.
+const MAX_RX_S: u32 = 10;
+
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7F;
+const CTRL_C: u8 = 0x03;
+
+/// What commands have access to.
+pub struct Context<'a> {
+    pub board: &'a mut Board,
+    pub flight_log: &'a mut FlightLog,
+    pub last_position: Option<&'a Beacon>,
+}
+
+pub struct Console {
+    line: ArrayString<MAX_LINE_LEN>,
+}
+impl Console {
+    /// Prints the first prompt.
+    pub fn new() -> Self {
+        print!("> ");
+        Self { line: ArrayString::new() }
+    }
+
+    /// Handle whatever has been typed since the last call, running the command if Enter was pressed.
+    pub fn poll(&mut self, context: &mut Context) {
+        while let Some(byte) = serial::read_byte() {
+            match byte {
+                b'\r' | b'\n' => {
+                    println!("");
+                    if !self.line.trim().is_empty() { run(&self.line, context) }
+                    self.line.clear();
+                    print!("> ");
+                },
+                BACKSPACE | DELETE => {
+                    if self.line.pop().is_some() { print!("\x08 \x08") }
+                },
+                CTRL_C => {
+                    self.line.clear();
+                    print!("\n> ");
+                },
+                b' '..=b'~' if !self.line.is_full() => {
+                    self.line.push(byte as char);
+                    print!("{}", byte as char);
+                },
+                _ => (), // Tabs, escape sequences, a full line and anything else we don't understand
+            }
+        }
+    }
+}
+
+/// Why a command failed.
+#[derive(Clone, Copy)]
+enum CommandError {
+    /// Missing or malformed arguments. The usage is printed.
+    Usage,
+    Failed(&'static str),
+}
+
+struct Command {
+    name: &'static str,
+    /// Arguments, for `help` and usage errors.
+    usage: &'static str,
+    help: &'static str,
+    run: fn(&mut Context, &[&str]) -> Result<(), CommandError>,
+}
+
+const COMMANDS: [Command; 9] = [
+    Command { name: "help", usage: "", help: "List commands", run: help },
+    Command { name: "status", usage: "", help: "Battery, power, rails and logging", run: status },
+    Command { name: "gps", usage: "", help: "Last GPS fix", run: gps },
+    Command { name: "radio", usage: "tx <text> | rx [seconds]", help: "Send or receive a raw packet", run: radio },
+    Command { name: "rails", usage: "[1v8|5v] [on|off]", help: "Show or switch PSU rails", run: rails },
+    Command { name: "config", usage: "get [name] | set <name> <value> | commit | defaults", help: "Per-payload settings", run: config_command },
+    Command { name: "log", usage: "dump | clear", help: "Flight log", run: log },
+    Command { name: "loglevel", usage: "[error|warn|info|debug|trace|off]", help: "Runtime log level", run: loglevel },
+    Command { name: "reboot", usage: "", help: "Reset the MCU", run: reboot },
+];
+
+/// Split `line` into words and run the command.
+fn run(line: &str, context: &mut Context) {
+    let mut args: ArrayVec<&str, MAX_ARGS> = ArrayVec::new();
+    for word in line.split_ascii_whitespace() {
+        if args.try_push(word).is_err() {
+            println!("Too many arguments");
+            return;
+        }
+    }
+    let Some(command) = COMMANDS.iter().find(|c| c.name == args[0]) else {
+        println!("Unknown command '{}', try 'help'", args[0]);
+        return;
+    };
+    match (command.run)(context, &args[1..]) {
+        Ok(()) => (),
+        Err(CommandError::Usage) => println!("Usage: {} {}", command.name, command.usage),
+        Err(CommandError::Failed(reason)) => println!("Failed: {}", reason),
+    }
+}
+
+/// Parse an argument, which must be there.
+fn parse<T: FromStr>(arg: Option<&&str>) -> Result<T, CommandError> {
+    arg.ok_or(CommandError::Usage)?.parse().map_err(|_| CommandError::Usage)
+}
+
+fn help(_: &mut Context, _: &[&str]) -> Result<(), CommandError> {
+    for command in COMMANDS.iter() {
+        println!("{} {}: {}", command.name, command.usage, command.help);
+    }
+    Ok(())
+}
+
+fn status(context: &mut Context, _: &[&str]) -> Result<(), CommandError> {
+    let board = &context.board;
+    println!("Boot #{}, reset cause {:?}, up {}s", board.boot_log.boot_count, board.reset_cause, lpm::now() / lpm::TICKS_PER_SECOND);
+    println!("Battery {:?}: {}mV, {}%", board.battery.level(), board.battery.voltage_mv(), board.battery.state_of_charge_percent());
+    println!("Power stage: {:?}, watchdog running: {}", board.power_stage(), board.watchdog.is_running());
+    println!("Debug serial: {} bytes dropped", serial::dropped_bytes());
+    for rail in [Rail::V1_8, Rail::V3_3, Rail::V5] {
+        println!("Rail {:?}: {:?}, {} faults", rail, board.rails.status(rail), board.rails.fault_count(rail));
+    }
+    println!("Flight log: {} records, launched: {}", context.flight_log.len(), context.flight_log.is_launched());
+    Ok(())
+}
+
+fn gps(context: &mut Context, _: &[&str]) -> Result<(), CommandError> {
+    match context.last_position {
+        Some(p) => println!("UTC {}ms, lat {}, lon {} (microdegrees), altitude {}dm, {} sats",
+            p.utc_millis, p.latitude_microdegrees, p.longitude_microdegrees, p.altitude_decimetres, p.num_satellites),
+        None => println!("No fix yet"),
+    }
+    Ok(())
+}
+
+fn radio(context: &mut Context, args: &[&str]) -> Result<(), CommandError> {
+    let radio = &mut context.board.radio;
+    let result = match args.first() {
+        Some(&"tx") if args.len() > 1 => {
+            let mut packet: ArrayString<MAX_LINE_LEN> = ArrayString::new();
+            for (i, word) in args[1..].iter().enumerate() {
+                if i > 0 { packet.push(' ') }
+                packet.push_str(word);
+            }
+            radio.transmit_start(packet.as_bytes()).map_err(|_| CommandError::Failed("couldn't start sending"))?;
+            nb::block!(radio.transmit_is_complete()).map_err(|_| CommandError::Failed("radio error"))?;
+            println!("Sent {} bytes", packet.len());
+            Ok(())
+        },
+        Some(&"rx") => {
+            let seconds = if args.len() > 1 { parse(args.get(1))? } else { MAX_RX_S }.min(MAX_RX_S);
+            let mut buf = [0u8; lora::RFM95_FIFO_SIZE];
+            radio.recieve_start(Some(Duration::from_secs(seconds as u64))).map_err(|_| CommandError::Failed("couldn't start listening"))?;
+            match nb::block!(radio.recieve_is_complete(&mut buf)) {
+                Ok(packet) => {
+                    print!("Received {} bytes:", packet.len());
+                    for byte in packet { print!(" {}", *byte) }
+                    println!("");
+                },
+                Err(RxCompleteError::TimeoutError(_)) => println!("Nothing received"),
+                Err(_) => println!("Corrupted packet"),
+            }
+            Ok(())
+        },
+        _ => Err(CommandError::Usage),
+    };
+    if config::RELAY_MODE {
+        radio.recieve_start(None).ok(); // Go back to listening for payloads to relay
+    }
+    result
+}
+
+fn rails(context: &mut Context, args: &[&str]) -> Result<(), CommandError> {
+    let board = &mut context.board;
+    if args.is_empty() {
+        for rail in [Rail::V1_8, Rail::V3_3, Rail::V5] {
+            println!("{:?}: {:?}", rail, board.rails.status(rail));
+        }
+        return Ok(());
+    }
+    let rail = match args[0] {
+        "1v8" => Rail::V1_8,
+        "5v" => Rail::V5,
+        _ => return Err(CommandError::Usage),
+    };
+    let result = match args.get(1) {
+        Some(&"on") => board.rails.enable(rail, &mut board.delay),
+        Some(&"off") => board.rails.disable(rail),
+        _ => return Err(CommandError::Usage),
+    };
+    result.map_err(|_| CommandError::Failed("rail didn't come up"))?;
+    println!("{:?}: {:?}", rail, board.rails.status(rail));
+    Ok(())
+}
+
+fn config_command(_: &mut Context, args: &[&str]) -> Result<(), CommandError> {
+    match args.first() {
+        None | Some(&"get") if args.len() <= 1 => config_store::print(),
+        Some(&"get") => {
+            let value = config_store::get().field(args[1]).ok_or(CommandError::Failed("no such setting"))?;
+            println!("{} = {}", args[1], value);
+        },
+        Some(&"set") => {
+            let name = args.get(1).ok_or(CommandError::Usage)?;
+            let mut config = config_store::get();
+            config.set_field(name, parse(args.get(2))?).map_err(|e| match e {
+                config_store::FieldError::UnknownField => CommandError::Failed("no such setting"),
+                config_store::FieldError::OutOfRange => CommandError::Failed("out of range"),
+            })?;
+            if let Err(e) = config.validate() {
+                println!("Not changed, invalid: {:?}", e);
+                return Ok(());
+            }
+            config_store::modify(|c| *c = config);
+            println!("Changed until reset. 'config commit' to keep it, then 'reboot' if it's a radio setting");
+        },
+        Some(&"commit") => {
+            config_store::commit().map_err(|_| CommandError::Failed("invalid config"))?;
+            println!("Saved");
+        },
+        Some(&"defaults") => {
+            config_store::reset_to_defaults();
+            println!("Using defaults until reset. 'config commit' to keep them");
+        },
+        _ => return Err(CommandError::Usage),
+    }
+    Ok(())
+}
+
+fn log(context: &mut Context, args: &[&str]) -> Result<(), CommandError> {
+    match args.first() {
+        Some(&"dump") => context.flight_log.dump_csv(),
+        Some(&"clear") => {
+            context.flight_log.clear();
+            println!("Flight log cleared");
+        },
+        _ => return Err(CommandError::Usage),
+    }
+    Ok(())
+}
+
+fn loglevel(_: &mut Context, args: &[&str]) -> Result<(), CommandError> {
+    match args.first() {
+        None => (),
+        Some(&"off") => log::set_level(None),
+        Some(name) => log::set_level(Some(log::Level::from_name(name).ok_or(CommandError::Usage)?)),
+    }
+    match log::level() {
+        Some(level) => println!("Log level: {}", level.name()),
+        None => println!("Logging off"),
+    }
+    Ok(())
+}
+
+fn reboot(_: &mut Context, _: &[&str]) -> Result<(), CommandError> {
+    println!("Rebooting");
+    serial::flush();
+    watchdog::reset_now()
+}
@@ -0,0 +1,126 @@
+// A record of the last panic, kept in information FRAM so it survives the reset that follows.
+//
+// The panic handler stores where the panic happened, the start of its message (if it's a plain string), the boot count
+// and the top of the stack, then resets. On the next boot, `take_unreported()` returns the record once, to be printed and
+// sent to the ground station in a `FrameKind::Crash` frame. The record itself stays in FRAM until the next panic
+// replaces it.
+
+use core::panic::PanicInfo;
+use crate::{boot_log, info_fram, print, println, protocol::CrashReport};
+
+/// Marks a valid record. Change it if the layout of `Stored` changes.
+const MAGIC: u16 = 0xC2A5;
+const FILE_LEN: usize = 32;
+const MESSAGE_LEN: usize = 48;
+/// Words copied from the top of the stack.
+const STACK_WORDS: usize = 16;
+/// One past the end of RAM.
+const RAM_END: usize = 0x3000;
+
+/// The record as stored in FRAM.
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct Stored {
+    magic: u16,
+    /// Non-zero once `take_unreported()` has returned this record.
+    reported: u16,
+    boot_count: u32,
+    line: u32,
+    column: u32,
+    stack_pointer: u16,
+    file_len: u8,
+    message_len: u8,
+    /// The end of the source file's path, which is the interesting part.
+    file: [u8; FILE_LEN],
+    /// The start of the panic message.
+    message: [u8; MESSAGE_LEN],
+    /// Starting at `stack_pointer`, i.e. the most recently pushed word first.
+    stack: [u16; STACK_WORDS],
+}
+
+pub struct CrashLog(Stored);
+impl CrashLog {
+    pub fn file(&self) -> &str {
+        core::str::from_utf8(&self.0.file[..self.0.file_len as usize]).unwrap_or("?")
+    }
+
+    pub fn message(&self) -> &str {
+        core::str::from_utf8(&self.0.message[..self.0.message_len as usize]).unwrap_or("?")
+    }
+
+    pub fn print(&self) {
+        let crash = &self.0;
+        println!("Crashed during boot #{} at {}:{}:{}: {}", crash.boot_count, self.file(), crash.line, crash.column, self.message());
+        print!("Stack from {}:", crash.stack_pointer);
+        for word in crash.stack {
+            print!(" {}", word);
+        }
+        println!("");
+    }
+
+    /// The crash in a form small enough to send over the radio.
+    pub fn report(&self) -> CrashReport<'_> {
+        let crash = &self.0;
+        CrashReport {
+            boot_count: crash.boot_count.min(u16::MAX as u32) as u16,
+            line: crash.line.min(u16::MAX as u32) as u16,
+            column: crash.column.min(u16::MAX as u32) as u16,
+            file: self.file().as_bytes(),
+            message: self.message().as_bytes(),
+        }
+    }
+}
+
+/// The last crash, if it hasn't been returned before. Call this once, early in boot.
+pub fn take_unreported() -> Option<CrashLog> {
+    let stored: Stored = unsafe { info_fram::read(info_fram::CRASH_LOG) };
+    if stored.magic != MAGIC || stored.reported != 0 { return None }
+
+    info_fram::write(info_fram::CRASH_LOG, &Stored { reported: 1, ..stored });
+    Some(CrashLog(stored))
+}
+
+/// Save a panic to FRAM. Only for the panic handler. Must not panic itself.
+pub fn record_panic(panic_info: &PanicInfo) {
+    let mut crash = Stored {
+        magic: MAGIC,
+        reported: 0,
+        boot_count: boot_log::stored_boot_count(),
+        line: 0,
+        column: 0,
+        stack_pointer: 0,
+        file_len: 0,
+        message_len: 0,
+        file: [0; FILE_LEN],
+        message: [0; MESSAGE_LEN],
+        stack: [0; STACK_WORDS],
+    };
+
+    if let Some(location) = panic_info.location() {
+        crash.line = location.line();
+        crash.column = location.column();
+        let file = location.file().as_bytes();
+        let tail = &file[file.len().saturating_sub(FILE_LEN)..];
+        crash.file[..tail.len()].copy_from_slice(tail);
+        crash.file_len = tail.len() as u8;
+    }
+
+    // Only plain messages are kept. Formatting the others would link in core::fmt, and the location is enough to find them.
+    if let Some(message) = panic_info.message().as_str() {
+        let mut len = message.len().min(MESSAGE_LEN);
+        while !message.is_char_boundary(len) { len -= 1 }
+        crash.message[..len].copy_from_slice(&message.as_bytes()[..len]);
+        crash.message_len = len as u8;
+    }
+
+    let stack_pointer: u16;
+    unsafe { core::arch::asm!("mov r1, {0}", out(reg) stack_pointer) };
+    crash.stack_pointer = stack_pointer;
+    for (i, word) in crash.stack.iter_mut().enumerate() {
+        let address = stack_pointer as usize + 2 * i;
+        if address >= RAM_END { break }
+        *word = unsafe { core::ptr::read_volatile(address as *const u16) };
+    }
+
+    info_fram::write(info_fram::CRASH_LOG, &crash);
+}
@@ -0,0 +1,244 @@
+// A flight data log in FRAM, so the flight can be reconstructed even if the downlink was lost.
+//
+// Records go into whatever program FRAM the code leaves free (see `memory.x`), split in two rings:
+// - Before launch, records go round the first `config::PRE_TRIGGER_RECORDS` slots, so only the last few minutes on the
+//   ground are kept.
+// - Once a launch is detected (the altitude climbs `Config::launch_altitude_gain_m` above the lowest seen), that ring
+//   is frozen and records go round the rest of the region instead. If it fills up, the oldest flight records go first.
+//
+// Where each ring has got to lives in a small header in information FRAM, so logging carries on after a reset mid-flight.
+// Clear the log before each flight, otherwise it still thinks it has launched. The header also remembers how many
+// records fitted, so a build of a different size starts a new log rather than reading the old one at the wrong offsets.
+//
+// The console's `log dump` command (see console.rs) prints the log as CSV, but a console build doesn't fit in flash
+// with a log any more (see README.md). So to read a flown log, read the memory back with the programmer instead: the
+// records are `Record`s, little-endian, from `_flight_log_start` to `_flight_log_end` in the ELF, and the header (at
+// `info_fram::FLIGHT_LOG`) says where each ring has got to. Do it before reflashing, as programmers usually erase all of
+// main FRAM, log included.
+//
+// FRAM has no erase cycle and an endurance of around 10^15 writes, so rewriting the header with every record won't wear
+// it out. Each record is written exactly once per trip round its ring.
+
+use core::mem::size_of;
+use msp430fr2355::SYS;
+use crate::{config, config_store, info_fram, protocol::Beacon};
+#[cfg(feature = "console")]
+use crate::println;
+
+extern "C" {
+    // Defined in memory.x
+    static _flight_log_start: u8;
+    static _flight_log_end: u8;
+}
+
+/// Marks a valid header. Change it if the layout of `Header` or `Record` changes.
+const MAGIC: u16 = 0xF10D;
+/// SYSCFG0 ignores writes unless its upper byte is this.
+const SYSCFG0_PASSWORD: u8 = 0xA5;
+/// Fixes in a row that must be above the launch altitude, so one bad fix can't trigger it.
+const LAUNCH_CONFIRM_FIXES: u8 = 3;
+
+/// Bits in `Record::flags`.
+pub mod flags {
+    /// Written after launch.
+    pub const LAUNCHED: u8 = 1 << 0;
+    /// The record written when launch was detected.
+    pub const LAUNCH: u8 = 1 << 1;
+    /// No GPS fix yet this boot, so there's no position.
+    pub const NO_FIX: u8 = 1 << 2;
+    pub const LOW_BATTERY: u8 = 1 << 3;
+    pub const RAIL_FAULT: u8 = 1 << 4;
+    /// The first record since we booted.
+    pub const BOOTED: u8 = 1 << 5;
+}
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct Record {
+    /// Counts up with every record, so the order survives the rings wrapping.
+    pub seq: u16,
+    /// See `flags`.
+    pub flags: u8,
+    /// Battery voltage in 20mV steps, so up to 5.1V fits in a byte.
+    pub battery_20mv: u8,
+    /// Seconds since midnight UTC, from the GPS.
+    pub utc_s: u32,
+    pub latitude_microdegrees: i32,
+    pub longitude_microdegrees: i32,
+    pub altitude_decimetres: i32,
+}
+impl Record {
+    /// A record of where we are, or just the battery and `NO_FIX` if we don't know yet.
+    pub fn new(position: Option<&Beacon>, battery_mv: u16, flags: u8) -> Self {
+        let battery_20mv = (battery_mv / 20).min(u8::MAX as u16) as u8;
+        match position {
+            Some(p) => Record { seq: 0, flags, battery_20mv, utc_s: p.utc_millis / 1000, latitude_microdegrees: p.latitude_microdegrees,
+                longitude_microdegrees: p.longitude_microdegrees, altitude_decimetres: p.altitude_decimetres },
+            None => Record { seq: 0, flags: flags | flags::NO_FIX, battery_20mv, utc_s: 0, latitude_microdegrees: 0,
+                longitude_microdegrees: 0, altitude_decimetres: 0 },
+        }
+    }
+}
+
+/// Where the log has got to, kept in information FRAM.
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct Header {
+    magic: u16,
+    /// Non-zero once launch has been detected.
+    launched: u16,
+    /// `seq` of the next record.
+    seq: u16,
+    /// Next slot to write in each ring, and how many of its slots hold records.
+    pre_next: u16,
+    pre_count: u16,
+    post_next: u16,
+    post_count: u16,
+    /// `slots()` when the log was started.
+    slots: u16,
+}
+impl Header {
+    fn empty() -> Self {
+        Self { magic: MAGIC, launched: 0, seq: 0, pre_next: 0, pre_count: 0, post_next: 0, post_count: 0, slots: slots() as u16 }
+    }
+}
+
+pub struct FlightLog {
+    header: Header,
+    /// Lowest altitude seen since boot, while waiting for launch.
+    ground_altitude_dm: Option<i32>,
+    fixes_above_launch_altitude: u8,
+    booted: bool,
+}
+impl FlightLog {
+    /// Pick up the log where it was left, or start a new one if there isn't a valid one.
+    pub fn open() -> Self {
+        let stored: Header = unsafe { info_fram::read(info_fram::FLIGHT_LOG) };
+        let header = if stored.magic == MAGIC && stored.slots == slots() as u16 { stored } else { Header::empty() };
+        Self { header, ground_altitude_dm: None, fixes_above_launch_altitude: 0, booted: true }
+    }
+
+    /// Throw away every record and wait for launch again.
+    #[cfg(feature = "console")]
+    pub fn clear(&mut self) {
+        self.header = Header::empty();
+        info_fram::write(info_fram::FLIGHT_LOG, &self.header);
+        self.ground_altitude_dm = None;
+        self.fixes_above_launch_altitude = 0;
+    }
+
+    pub fn is_launched(&self) -> bool {
+        self.header.launched != 0
+    }
+
+    /// Number of records in the log.
+    pub fn len(&self) -> usize {
+        (self.header.pre_count + self.header.post_count) as usize
+    }
+
+    #[allow(dead_code)] // Goes with len()
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Feed in each GPS altitude to look for launch. Returns `true` once, when launch is detected.
+    pub fn check_launch(&mut self, altitude_decimetres: i32) -> bool {
+        if self.is_launched() { return false }
+
+        let ground = self.ground_altitude_dm.map_or(altitude_decimetres, |g| g.min(altitude_decimetres));
+        self.ground_altitude_dm = Some(ground);
+        if altitude_decimetres > ground + config_store::get().launch_altitude_gain_m as i32 * 10 {
+            self.fixes_above_launch_altitude += 1;
+        } else {
+            self.fixes_above_launch_altitude = 0;
+        }
+        if self.fixes_above_launch_altitude < LAUNCH_CONFIRM_FIXES { return false }
+
+        self.header.launched = 1;
+        info_fram::write(info_fram::FLIGHT_LOG, &self.header);
+        true
+    }
+
+    /// Add a record, overwriting the oldest in its ring if that's full. `seq` is filled in here, and the `LAUNCHED` and
+    /// `BOOTED` flags are added as needed.
+    pub fn append(&mut self, mut record: Record) {
+        record.seq = self.header.seq;
+        if self.is_launched() { record.flags |= flags::LAUNCHED }
+        if self.booted { record.flags |= flags::BOOTED }
+        self.booted = false;
+
+        let header = &mut self.header;
+        let pre_len = pre_trigger_slots() as u16;
+        let post_len = (slots() - pre_trigger_slots()) as u16;
+        let slot = if header.launched == 0 {
+            let slot = header.pre_next;
+            header.pre_next = (slot + 1) % pre_len;
+            header.pre_count = (header.pre_count + 1).min(pre_len);
+            slot
+        } else {
+            let slot = header.post_next;
+            header.post_next = (slot + 1) % post_len;
+            header.post_count = (header.post_count + 1).min(post_len);
+            pre_len + slot
+        };
+        header.seq = header.seq.wrapping_add(1);
+
+        write_record(slot as usize, &record);
+        info_fram::write(info_fram::FLIGHT_LOG, &self.header);
+    }
+
+    /// Records in the order they were written: the pre-launch ring, then the post-launch ring, each oldest first.
+    #[cfg(feature = "console")]
+    pub fn records(&self) -> impl Iterator<Item = Record> + '_ {
+        let pre_len = pre_trigger_slots();
+        let post_len = slots() - pre_len;
+        let h = &self.header;
+        let oldest = |next: u16, count: u16, len: usize| if count as usize == len { next as usize } else { 0 };
+
+        let pre_oldest = oldest(h.pre_next, h.pre_count, pre_len);
+        let post_oldest = oldest(h.post_next, h.post_count, post_len);
+        let pre = (0..h.pre_count as usize).map(move |i| (pre_oldest + i) % pre_len);
+        let post = (0..h.post_count as usize).map(move |i| pre_len + (post_oldest + i) % post_len);
+        pre.chain(post).map(read_record)
+    }
+
+    /// Print every record as CSV.
+    #[cfg(feature = "console")]
+    pub fn dump_csv(&self) {
+        println!("seq,utc_s,lat_udeg,lon_udeg,alt_dm,battery_mv,flags");
+        for r in self.records() {
+            println!("{},{},{},{},{},{},{}", r.seq, r.utc_s, r.latitude_microdegrees, r.longitude_microdegrees,
+                r.altitude_decimetres, r.battery_20mv as u16 * 20, r.flags);
+        }
+    }
+}
+
+fn start_address() -> usize {
+    core::ptr::addr_of!(_flight_log_start) as usize
+}
+
+/// Number of records the region holds.
+fn slots() -> usize {
+    let end = core::ptr::addr_of!(_flight_log_end) as usize;
+    (end - start_address()) / size_of::<Record>()
+}
+
+fn pre_trigger_slots() -> usize {
+    (config::PRE_TRIGGER_RECORDS as usize).min(slots() / 2)
+}
+
+#[cfg(feature = "console")]
+fn read_record(slot: usize) -> Record {
+    unsafe { core::ptr::read_volatile((start_address() + slot * size_of::<Record>()) as *const Record) }
+}
+
+/// Program FRAM is write protected (SYSCFG0.PFWP), so lift that for just as long as it takes.
+fn write_record(slot: usize, record: &Record) {
+    let address = start_address() + slot * size_of::<Record>();
+    let sys = unsafe { &*SYS::ptr() };
+    msp430::critical_section::with(|_| {
+        sys.syscfg0.modify(|r, w| unsafe { w.bits(r.bits() & 0x00FF).frwppw().bits(SYSCFG0_PASSWORD) }.pfwp().clear_bit());
+        unsafe { core::ptr::write_volatile(address as *mut Record, *record) };
+        sys.syscfg0.modify(|r, w| unsafe { w.bits(r.bits() & 0x00FF).frwppw().bits(SYSCFG0_PASSWORD) }.pfwp().set_bit());
+    });
+}
@@ -1,6 +1,6 @@
 #![allow(dead_code)]
 
-use core::{fmt::Debug, num::ParseIntError};
+use core::{fmt::Debug, num::ParseIntError, ops::Range};
 
 use arrayvec::{ArrayString, ArrayVec};
 use msp430fr2x5x_hal::{
@@ -50,12 +50,14 @@ impl Gps {
             }
             return Err(nb::Error::WouldBlock);
         }
+        if buf.try_push(chr as char).is_err() { // Too long to be a real sentence, so drop it and wait for the next one
+            self.rx_started = false;
+            return Err(nb::Error::WouldBlock);
+        }
         if chr == b'\n' { // Message has finished
-            buf.push('\n');
             self.rx_started = false;
             return Ok(());
         }
-        buf.push(chr as char);
         Err(nb::Error::WouldBlock)
     }
 
@@ -69,7 +71,7 @@ impl Gps {
     pub fn get_gga_message_string(&mut self, buf: &mut ArrayString::<NMEA_MESSAGE_MAX_LEN>) -> nb::Result<(), RecvError> {
         self.get_nmea_message_string(buf)?;
 
-        if &buf[3..6] == "GGA" { Ok(()) } 
+        if buf.get(3..6) == Some("GGA") { Ok(()) } 
         else {
             Err(nb::Error::WouldBlock)
         }
@@ -89,6 +91,22 @@ impl Gps {
             Err(nb::Error::Other(e)) => Err(nb::Error::Other(GgaParseError::SerialError(e))),
         }
     }
+
+    /// Get a GPS RMC packet as a struct. Unlike GGA this includes ground speed and course, but not altitude.
+    /// 
+    /// Slowly builds up an RMC message byte by byte by checking the serial buffer. Call this function repeatedly until it returns `Ok`.
+    /// 
+    /// This function must be called sufficiently frequently to ensure that the serial buffer does not overrun.
+    /// 
+    /// After this function returns `Ok(())`, calling it again will clear the buffer to prepare for the next message.
+    pub fn get_rmc_message(&mut self, buf: &mut ArrayString::<NMEA_MESSAGE_MAX_LEN>) -> nb::Result<RmcMessage, RmcParseError> {
+        match self.get_nmea_message_string(buf) {
+            Ok(_) if buf.get(3..6) == Some("RMC") => Ok( RmcMessage::try_from(&*buf)? ),
+            Ok(_) => Err(nb::Error::WouldBlock),
+            Err(nb::Error::WouldBlock) => Err(nb::Error::WouldBlock),
+            Err(nb::Error::Other(e)) => Err(nb::Error::Other(RmcParseError::SerialError(e))),
+        }
+    }
 }
 
 // A GGA packet in struct form. Useful for interpreting the results on-device.
@@ -111,11 +129,11 @@ impl TryFrom<&ArrayString<NMEA_MESSAGE_MAX_LEN>> for GgaMessage {
         if fix_type == GpsFixType::None { return Err(GgaParseError::NoFix) }
 
         Ok( GgaMessage { 
-            utc_time: UtcTime::try_from(sections[1])                .unwrap(),//.map_err(GgaParseError::UtcParseError)?, 
-            latitude:  Degrees::try_from((sections[2], sections[3])).unwrap(),//.map_err(GgaParseError::LatLongParseError)?, 
-            longitude: Degrees::try_from((sections[4], sections[5])).unwrap(),//.map_err(GgaParseError::LatLongParseError)?, 
-            num_satellites: sections[7].parse()                     .unwrap(),//.map_err(GgaParseError::InvalidSatelliteNumber)?, 
-            altitude_msl: Altitude::try_from(sections[9])           .unwrap(),//.map_err(GgaParseError::AltitudeParseError)?, 
+            utc_time: UtcTime::try_from(sections[1])                .map_err(GgaParseError::UtcParseError)?,
+            latitude:  Degrees::try_from((sections[2], sections[3])).map_err(GgaParseError::LatLongParseError)?,
+            longitude: Degrees::try_from((sections[4], sections[5])).map_err(GgaParseError::LatLongParseError)?,
+            num_satellites: parse_u32(sections[7])                  .map_err(GgaParseError::InvalidSatelliteNumber)? as u8,
+            altitude_msl: Altitude::try_from(sections[9])           .map_err(GgaParseError::AltitudeParseError)?,
             fix_type,
         })
     }
@@ -147,6 +165,62 @@ impl Debug for GgaParseError {
     }
 }
 
+// An RMC packet in struct form.
+pub struct RmcMessage {
+    pub utc_time: UtcTime,
+    pub latitude: Degrees,
+    pub longitude: Degrees,
+    /// Speed over ground in tenths of a knot.
+    pub speed_tenths_knots: u32,
+    /// Course over ground in tenths of a degree clockwise from true north. Meaningless when stationary.
+    pub course_tenths_degrees: u16,
+}
+impl TryFrom<&ArrayString<NMEA_MESSAGE_MAX_LEN>> for RmcMessage {
+    type Error = RmcParseError;
+
+    fn try_from(msg: &ArrayString<NMEA_MESSAGE_MAX_LEN>) -> Result<Self, Self::Error> {
+        // Older receivers omit the mode indicator at the end, so there may be 12 or 13 sections
+        let sections: ArrayVec<&str, 13> = msg.split(',').take(13).collect();
+        if sections.len() < 12 { return Err(RmcParseError::WrongSectionCount) }
+
+        if sections[2] != "A" { return Err(RmcParseError::NoFix) }
+
+        Ok( RmcMessage {
+            utc_time: UtcTime::try_from(sections[1]).map_err(RmcParseError::UtcParseError)?,
+            latitude:  Degrees::try_from((sections[3], sections[4])).map_err(RmcParseError::LatLongParseError)?,
+            longitude: Degrees::try_from((sections[5], sections[6])).map_err(RmcParseError::LatLongParseError)?,
+            speed_tenths_knots: parse_tenths(sections[7]).map_err(RmcParseError::SpeedParseError)?,
+            course_tenths_degrees: parse_tenths(sections[8]).map_err(RmcParseError::CourseParseError)? as u16,
+        })
+    }
+}
+
+pub enum RmcParseError {
+    NoFix,
+    SerialError(RecvError),
+    WrongSectionCount,
+    LatLongParseError(LatLongParseError),
+    UtcParseError(UtcError),
+    SpeedParseError(ParseIntError),
+    CourseParseError(ParseIntError),
+}
+
+/// Parse a decimal like "12.34" into tenths, i.e. 123. Empty fields are treated as zero.
+fn parse_tenths(value: &str) -> Result<u32, ParseIntError> {
+    if value.is_empty() { return Ok(0) }
+    let (whole, frac) = value.split_once('.').unwrap_or((value, "0"));
+    let tenth = match frac.get(..1) {
+        Some(digit) => parse_u32(digit)?,
+        None => 0,
+    };
+    Ok(parse_u32(whole)? * 10 + tenth)
+}
+
+/// Every field is parsed as a `u32` and then narrowed, so only one copy of `str::parse` is linked in.
+fn parse_u32(value: &str) -> Result<u32, ParseIntError> {
+    value.parse()
+}
+
 /// A UTC timestamp
 pub struct UtcTime {
     pub hours: u8,
@@ -154,6 +228,12 @@ pub struct UtcTime {
     pub seconds: u8,
     pub millis: u16, 
 }
+impl UtcTime {
+    /// Milliseconds since midnight.
+    pub fn millis_of_day(&self) -> u32 {
+        ((self.hours as u32 * 60 + self.minutes as u32) * 60 + self.seconds as u32) * 1000 + self.millis as u32
+    }
+}
 impl uDisplay for UtcTime {
     fn fmt<W>(&self, f: &mut ufmt::Formatter<'_, W>) -> Result<(), W::Error>
     where
@@ -183,14 +263,13 @@ impl TryFrom<&str> for UtcTime {
     type Error = UtcError;
 
     fn try_from(value: &str) -> Result<Self, Self::Error> {
-        if value.len() < 6 {
-            return Err(UtcError::StrTooShort)
-        }
+        // `get()` rather than indexing, as a panic on a bad string would pull in core::fmt's char formatting
+        let field = |range: Range<usize>| value.get(range).ok_or(UtcError::StrTooShort);
         Ok(UtcTime { 
-            hours: value[0..2].parse().map_err(UtcError::ParseError)?, 
-            minutes: value[2..4].parse().map_err(UtcError::ParseError)?, 
-            seconds: value[4..6].parse().map_err(UtcError::ParseError)?, 
-            millis: value.get(7..).unwrap_or("0").parse().map_err(UtcError::ParseError)? })
+            hours: parse_u32(field(0..2)?).map_err(UtcError::ParseError)? as u8,
+            minutes: parse_u32(field(2..4)?).map_err(UtcError::ParseError)? as u8,
+            seconds: parse_u32(field(4..6)?).map_err(UtcError::ParseError)? as u8,
+            millis: parse_u32(value.get(7..).unwrap_or("0")).map_err(UtcError::ParseError)? as u16 })
     }
 }
 #[derive(Debug)]
@@ -201,8 +280,17 @@ pub enum UtcError {
 
 /// A degrees value, stored as a decimal fraction.
 pub struct Degrees {
-    degrees: i16,
+    degrees: u16,
     degrees_millionths: u32,
+    /// South or west. Kept separately, as `degrees` is 0 for values under 1 degree.
+    negative: bool,
+}
+impl Degrees {
+    /// The value in millionths of a degree. Negative for south/west.
+    pub fn as_microdegrees(&self) -> i32 {
+        let magnitude = self.degrees as i32 * 1_000_000 + self.degrees_millionths as i32;
+        if self.negative { -magnitude } else { magnitude }
+    }
 }
 impl uDisplay for Degrees {
     fn fmt<W>(&self, f: &mut ufmt::Formatter<'_, W>) -> Result<(), W::Error>
@@ -213,7 +301,8 @@ impl uDisplay for Degrees {
             leading_zeroes.push('0');
         }
 
-        uwrite!(f, "{}.{}{} deg", self.degrees, leading_zeroes.as_str(), self.degrees_millionths)
+        let sign = if self.negative { "-" } else { "" };
+        uwrite!(f, "{}{}.{}{} deg", sign, self.degrees, leading_zeroes.as_str(), self.degrees_millionths)
     }
 }
 impl TryFrom<(&str, &str)> for Degrees {
@@ -224,31 +313,32 @@ impl TryFrom<(&str, &str)> for Degrees {
         if degrees_str.is_empty() || compass_direction.is_empty() {
             return Err(LatLongParseError::NoData);
         }
-        let degrees: i16; 
+        let degrees: u16; 
         let minutes_str: &str;
         let minutes_frac_str: &str;
-        let (first_half, _) = degrees_str.split_once('.').unwrap();
+        let (first_half, _) = degrees_str.split_once('.').ok_or(LatLongParseError::NoData)?;
+        let field = |range: Range<usize>| degrees_str.get(range).ok_or(LatLongParseError::NoData);
     
         if first_half.len() == 4 { // ddmm
-            degrees          =  degrees_str[0..2].parse().unwrap();
-            minutes_str      = &degrees_str[2..4];
-            minutes_frac_str = &degrees_str[5..9];
+            degrees          = parse_u32(field(0..2)?).map_err(LatLongParseError::ParseError)? as u16;
+            minutes_str      = field(2..4)?;
+            minutes_frac_str = field(5..9)?;
 
         } else { // dddmm
-            degrees          =  degrees_str[0..3].parse().unwrap();
-            minutes_str      = &degrees_str[3..5];
-            minutes_frac_str = &degrees_str[6..10];
+            degrees          = parse_u32(field(0..3)?).map_err(LatLongParseError::ParseError)? as u16;
+            minutes_str      = field(3..5)?;
+            minutes_frac_str = field(6..10)?;
         }
 
         // 24.3761 -> 243761
-        let mut minutes_times_10000 = ArrayString::<6>::from(minutes_str).unwrap();
-        minutes_times_10000.push_str(minutes_frac_str);
+        let minutes_times_10000 = parse_u32(minutes_str).map_err(LatLongParseError::ParseError)? * 10_000
+            + parse_u32(minutes_frac_str).map_err(LatLongParseError::ParseError)?;
 
-        let degrees_millionths: u32 = minutes_times_10000.parse::<u32>().unwrap() * 100 / 60;
+        let degrees_millionths: u32 = minutes_times_10000 * 100 / 60;
     
         match compass_direction {
-            "N" | "E" => Ok(Degrees{degrees,            degrees_millionths}),
-            "S" | "W" => Ok(Degrees{degrees: -degrees,  degrees_millionths}),
+            "N" | "E" => Ok(Degrees{degrees, degrees_millionths, negative: false}),
+            "S" | "W" => Ok(Degrees{degrees, degrees_millionths, negative: true}),
             _ => Err(LatLongParseError::InvalidCompassDirection)
         }
     }
@@ -257,6 +347,7 @@ impl TryFrom<(&str, &str)> for Degrees {
 pub enum LatLongParseError {
     NoData,
     InvalidCompassDirection,
+    ParseError(ParseIntError),
 }
 
 #[derive(Debug, uDebug, PartialEq, Eq)]
@@ -281,12 +372,22 @@ impl TryFrom<&str> for GpsFixType{
 pub struct Altitude{
     decimetres: i32,
 }
+impl Altitude {
+    pub fn decimetres(&self) -> i32 {
+        self.decimetres
+    }
+}
 impl TryFrom<&str> for Altitude {
     type Error = ParseIntError;
 
     fn try_from(value: &str) -> Result<Self, Self::Error> {
-        let (whole, frac) = value.split_once(".").unwrap();
-        Ok(Altitude{ decimetres: whole.parse::<i32>()?*10 + frac[..1].parse::<i32>()?})
+        let (whole, frac) = value.split_once('.').unwrap_or((value, "0"));
+        let (negative, whole) = match whole.strip_prefix('-') {
+            Some(whole) => (true, whole),
+            None => (false, whole),
+        };
+        let decimetres = (parse_u32(whole)? * 10 + parse_u32(frac.get(..1).unwrap_or("0"))?) as i32;
+        Ok(Altitude{ decimetres: if negative { -decimetres } else { decimetres } })
     }
 }
 impl uDisplay for Altitude {
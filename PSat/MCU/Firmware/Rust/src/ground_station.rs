@@ -0,0 +1,176 @@
+// Ground station mode. Flash this onto a board with a beacon slice attached (no GPS fix required) and leave it plugged
+// into a computer. Beacons from every payload on the channel are printed over the debug serial.
+// To use it, call `ground_station::run(board)` from `main()` instead of the payload loop.
+//
+// With `config::ADAPTIVE_DATA_RATE` enabled the ground station also ACKs every beacon it hears.
+
+#![allow(dead_code)]
+use core::time::Duration;
+use embedded_hal::timer::CountDown;
+use embedded_lora_rfm95::error::RxCompleteError;
+use psat_link::hopping::{FollowerState, HopFollower};
+use crate::{adr, board::Board, config, config_store, relay::DuplicateCache, println, protocol::{Ack, Beacon, CrashReport, Frame, FrameHeader, FrameKind, GROUND_STATION_ID}};
+
+/// Listen in short windows, so that we can retune soon after deciding to without interrupting a reception.
+const LISTEN_WINDOW: Duration = Duration::from_secs(1);
+
+pub fn run(mut board: Board) -> ! {
+    board.radio.set_address(GROUND_STATION_ID);
+
+    // If a payload using adaptive data rate goes quiet, we have to assume it has fallen back to the most robust profile.
+    let config = config_store::get();
+    let fallback_after_ms = config.beacon_interval_ms() * adr::MAX_MISSED_ACKS as u32;
+    let mut ms_since_beacon: u32 = 0;
+    let mut profile = config.link_profile;
+    let mut profile_pending = false;
+    let mut seen = DuplicateCache::new();
+
+    let mut buf = [0u8; crate::lora::RFM95_FIFO_SIZE];
+    board.timer_b0.start(msp430fr2x5x_hal::clock::REFOCLK); // 1 second timer
+    board.radio.recieve_start(Some(LISTEN_WINDOW)).unwrap();
+    loop {
+        match board.radio.recieve_frame_is_complete(&mut buf) {
+            Err(nb::Error::Other(RxCompleteError::TimeoutError(_))) => {
+                if profile_pending {
+                    board.radio.set_profile(&adr::PROFILES[profile as usize]).unwrap();
+                    profile_pending = false;
+                }
+                board.radio.recieve_start(Some(LISTEN_WINDOW)).unwrap();
+            },
+            Err(_e) => (),
+            Ok(frame) => {
+                // Relaying payloads mean we can hear the same frame more than once
+                if seen.check_and_insert(&frame.header) {
+                    board.radio.recieve_start(Some(LISTEN_WINDOW)).unwrap();
+                    continue;
+                }
+                print_frame(&mut board, &frame);
+
+                // The signal quality of a relayed frame tells the payload nothing about its own link, so only ACK direct ones
+                if config::ADAPTIVE_DATA_RATE && frame.header.kind == FrameKind::Beacon && frame.header.hops() == 0 {
+                    if let Ok(beacon) = Beacon::from_bytes(frame.payload) {
+                        send_ack(&mut board, &frame.header);
+                        ms_since_beacon = 0;
+
+                        // The payload switches once it hears our ACK, so we switch too
+                        if beacon.next_profile != profile && (beacon.next_profile as usize) < adr::PROFILES.len() {
+                            profile = beacon.next_profile;
+                            board.radio.set_profile(&adr::PROFILES[profile as usize]).unwrap();
+                        }
+                    }
+                }
+                board.radio.recieve_start(Some(LISTEN_WINDOW)).unwrap();
+            },
+        }
+
+        if config::ADAPTIVE_DATA_RATE && board.timer_b0.wait().is_ok() {
+            ms_since_beacon = ms_since_beacon.saturating_add(1000);
+            if ms_since_beacon >= fallback_after_ms && profile != adr::FALLBACK_PROFILE {
+                println!("No beacons, falling back to profile {}", adr::FALLBACK_PROFILE);
+                profile = adr::FALLBACK_PROFILE;
+                profile_pending = true;
+            }
+        }
+    }
+}
+
+/// Report the signal quality of the frame we just recieved back to its sender. Blocks until the ACK has been sent.
+fn send_ack(board: &mut Board, header: &FrameHeader) {
+    let ack = Ack {
+        acked_seq: header.seq,
+        snr: board.radio.driver.get_packet_snr().unwrap_or(i8::MIN),
+        rssi: board.radio.driver.get_packet_rssi().unwrap_or(i16::MIN),
+    };
+    if board.radio.transmit_frame_start(header.src, FrameKind::Ack, &ack.to_bytes()).is_ok() {
+        let _ = nb::block!(board.radio.transmit_is_complete());
+    }
+}
+
+/// Like `run()`, but follows a single payload that has `config::FREQUENCY_HOPPING` enabled.
+///
+/// Acquisition can take up to `psat_link::hopping::NUM_CHANNELS` beacon intervals, be patient.
+pub fn run_hopping(mut board: Board, payload_id: u8) -> ! {
+    board.radio.set_address(GROUND_STATION_ID);
+
+    // Give the payload half an interval of leeway before deciding we missed it.
+    let beacon_interval_ms = config_store::get().beacon_interval_ms();
+    let missed_after_ms = beacon_interval_ms + beacon_interval_ms / 2;
+
+    let mut follower = HopFollower::new(payload_id);
+    let mut ms_since_frame: u32 = 0;
+    let mut retune_pending = false;
+
+    let mut buf = [0u8; crate::lora::RFM95_FIFO_SIZE];
+    board.timer_b0.start(msp430fr2x5x_hal::clock::REFOCLK); // 1 second timer
+    board.radio.set_frequency(follower.frequency_hz()).unwrap();
+    board.radio.recieve_start(Some(LISTEN_WINDOW)).unwrap();
+    loop {
+        match board.radio.recieve_frame_is_complete(&mut buf) {
+            Err(nb::Error::Other(RxCompleteError::TimeoutError(_))) => {
+                if retune_pending {
+                    board.radio.set_frequency(follower.frequency_hz()).unwrap();
+                    retune_pending = false;
+                }
+                board.radio.recieve_start(Some(LISTEN_WINDOW)).unwrap();
+            },
+            Err(_e) => (),
+            Ok(frame) => {
+                if frame.header.src == payload_id {
+                    print_frame(&mut board, &frame);
+                    if follower.state() == FollowerState::Acquiring {
+                        println!("Acquired payload {}", payload_id);
+                    }
+                    follower.frame_received();
+                    ms_since_frame = 0;
+                    board.radio.set_frequency(follower.frequency_hz()).unwrap();
+                    retune_pending = false;
+                }
+                board.radio.recieve_start(Some(LISTEN_WINDOW)).unwrap();
+            },
+        }
+
+        if board.timer_b0.wait().is_ok() {
+            match follower.state() {
+                FollowerState::Acquiring => ms_since_frame = 0,
+                FollowerState::Tracking { .. } => {
+                    ms_since_frame += 1000;
+                    if ms_since_frame >= missed_after_ms {
+                        // The next frame is due one interval after the one we just missed
+                        ms_since_frame -= beacon_interval_ms;
+                        follower.hop_missed();
+                        retune_pending = true;
+                        if follower.state() == FollowerState::Acquiring {
+                            println!("Lost payload {}, reacquiring", payload_id);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn print_frame(board: &mut Board, frame: &Frame) {
+    let header = frame.header;
+    match header.kind {
+        FrameKind::Beacon => {
+            if let Ok(beacon) = Beacon::from_bytes(frame.payload) {
+                let rssi = board.radio.driver.get_packet_rssi().unwrap_or(0);
+                println!("Payload {} #{}: Lat: {}, Long: {}, Alt: {}dm, Sats: {}, RSSI: {}, Hops: {}, Rail faults: 1.8V {} 3.3V {}, Boots: {}",
+                    header.src, header.seq, beacon.latitude_microdegrees, beacon.longitude_microdegrees,
+                    beacon.altitude_decimetres, beacon.num_satellites, rssi, header.hops(),
+                    beacon.rail_faults & 0x0F, beacon.rail_faults >> 4, beacon.boot_count
+                );
+            }
+        },
+        FrameKind::RangeTest => (), // Use lora::tests::range_test_rx() for these
+        FrameKind::Ack => (),
+        FrameKind::Crash => {
+            if let Ok(crash) = CrashReport::from_bytes(frame.payload) {
+                let text = |bytes| core::str::from_utf8(bytes).unwrap_or("?");
+                println!("Payload {} crashed during boot #{} at {}:{}:{}: {}",
+                    header.src, crash.boot_count, text(crash.file), crash.line, crash.column, text(crash.message)
+                );
+            }
+        },
+    }
+}
@@ -0,0 +1,196 @@
+// Deferred ("interned") logging, for when the log strings don't fit in flash. Enabled with the `deferred-log` feature.
+//
+// With it on, `error!` to `trace!` (see log.rs) don't send text. Each format string goes in the `.ilog` section of the
+// ELF, which memory.x keeps out of flash, and the payload only sends where it is and the values of the arguments:
+//
+// | Byte | Contents                                                    |
+// |------|-------------------------------------------------------------|
+// | 0    | `RECORD_START`, which never appears in text                 |
+// | 1    | Length of the rest of the record                            |
+// | 2    | `log::Level`, or `PRINT`                                    |
+// | 3-4  | Address of the string, little endian                        |
+// | 5..  | Arguments, each a `tag` then its value (little endian)      |
+//
+// The string is "<module path>|<format string>", NUL terminated. To read the logs, pipe the debug UART into the
+// decoder in `../log_decoder` along with the ELF that was flashed. `print!` and `println!` are interned the same way,
+// with level `PRINT`, so the decoder prints them without a prefix. Anything else sent as text is passed through.
+//
+// Integers and bools are sent in binary. Anything else is formatted with `uDisplay` (or failing that, `uDebug`) and sent
+// as a string, so those implementations still take up flash.
+
+use core::convert::Infallible;
+use arrayvec::{ArrayString, ArrayVec};
+use ufmt::{uDebug, uDisplay, uWrite, uwrite};
+use crate::serial;
+
+/// Starts every record. Not ASCII, so it can't be mistaken for text.
+pub const RECORD_START: u8 = 0xFE;
+/// The level of records from `print!` and `println!`.
+pub const PRINT: u8 = 0;
+/// Longest record, including the start and length bytes. Arguments that don't fit are left off.
+const MAX_RECORD_LEN: usize = 64;
+/// Longest string argument. Longer ones are cut short.
+const MAX_STR_LEN: usize = 32;
+
+/// Argument types.
+pub mod tag {
+    pub const U8: u8 = 1;
+    pub const U16: u8 = 2;
+    pub const U32: u8 = 3;
+    pub const I8: u8 = 4;
+    pub const I16: u8 = 5;
+    pub const I32: u8 = 6;
+    pub const BOOL: u8 = 7;
+    /// A length byte, then that many bytes of UTF-8.
+    pub const STR: u8 = 8;
+}
+
+pub struct Record {
+    buf: ArrayVec<u8, MAX_RECORD_LEN>,
+}
+impl Record {
+    /// `level` is a `log::Level` or `PRINT`, and `string` is the address of the interned string.
+    pub fn new(level: u8, string: u16) -> Self {
+        let mut buf = ArrayVec::new();
+        let [lo, hi] = string.to_le_bytes();
+        buf.extend([RECORD_START, 0, level, lo, hi]);
+        Self { buf }
+    }
+
+    /// Add an argument, if there's room for all of it.
+    fn arg(&mut self, tag: u8, value: &[u8]) {
+        if self.buf.remaining_capacity() < 1 + value.len() { return }
+        self.buf.push(tag);
+        self.buf.try_extend_from_slice(value).ok();
+    }
+
+    fn str(&mut self, s: &str) {
+        let mut len = s.len().min(MAX_STR_LEN);
+        while !s.is_char_boundary(len) { len -= 1 }
+        let s = &s.as_bytes()[..len];
+        if self.buf.remaining_capacity() < 2 + s.len() { return }
+        self.buf.extend([tag::STR, s.len() as u8]);
+        self.buf.try_extend_from_slice(s).ok();
+    }
+
+    #[inline(never)]
+    fn display<T: uDisplay + ?Sized>(&mut self, value: &T) {
+        let mut s = Truncating(ArrayString::new());
+        uwrite!(s, "{}", value).ok();
+        self.str(&s.0);
+    }
+
+    #[inline(never)]
+    fn debug<T: uDebug + ?Sized>(&mut self, value: &T) {
+        let mut s = Truncating(ArrayString::new());
+        uwrite!(s, "{:?}", value).ok();
+        self.str(&s.0);
+    }
+
+    pub fn send(mut self) {
+        self.buf[1] = (self.buf.len() - 2) as u8;
+        serial::write_bytes(&self.buf);
+    }
+}
+
+/// Copy a string into a NUL-terminated array, for putting in `.ilog`.
+pub const fn nul_terminated<const N: usize>(s: &str) -> [u8; N] {
+    let bytes = s.as_bytes();
+    let mut out = [0; N];
+    let mut i = 0;
+    while i < bytes.len() {
+        out[i] = bytes[i];
+        i += 1;
+    }
+    out
+}
+
+// Picking how to encode an argument. Method lookup tries each of these in turn, so integers are sent in binary even
+// though they also implement `uDisplay`. The `ilog!` macro calls `(&&&Arg(&value)).encode(..)`. The `encode`s are
+// inlined so call sites don't have to build the chain of references, leaving one call per argument.
+pub struct Arg<'a, T: ?Sized>(pub &'a T);
+
+pub trait EncodeValue {
+    fn encode(&self, record: &mut Record);
+}
+macro_rules! encode_value {
+    ($($type:ty => $tag:expr),*) => {
+        $(impl EncodeValue for &&Arg<'_, $type> {
+            #[inline(always)]
+            fn encode(&self, record: &mut Record) {
+                record.arg($tag, &self.0.to_le_bytes());
+            }
+        })*
+    };
+}
+encode_value!(u8 => tag::U8, u16 => tag::U16, u32 => tag::U32, i8 => tag::I8, i16 => tag::I16, i32 => tag::I32);
+impl EncodeValue for &&Arg<'_, bool> {
+    #[inline(always)]
+    fn encode(&self, record: &mut Record) {
+        record.arg(tag::BOOL, &[*self.0 as u8]);
+    }
+}
+impl EncodeValue for &&Arg<'_, &str> {
+    #[inline(always)]
+    fn encode(&self, record: &mut Record) {
+        record.str(self.0);
+    }
+}
+
+pub trait EncodeDisplay {
+    fn encode(&self, record: &mut Record);
+}
+impl<T: uDisplay + ?Sized> EncodeDisplay for &Arg<'_, T> {
+    #[inline(always)]
+    fn encode(&self, record: &mut Record) {
+        record.display(self.0);
+    }
+}
+
+pub trait EncodeDebug {
+    fn encode(&self, record: &mut Record);
+}
+impl<T: uDebug + ?Sized> EncodeDebug for Arg<'_, T> {
+    #[inline(always)]
+    fn encode(&self, record: &mut Record) {
+        record.debug(self.0);
+    }
+}
+
+/// Formats into a string, dropping whatever doesn't fit.
+struct Truncating(ArrayString<MAX_STR_LEN>);
+impl uWrite for Truncating {
+    type Error = Infallible;
+
+    fn write_str(&mut self, s: &str) -> Result<(), Infallible> {
+        let mut len = s.len().min(self.0.remaining_capacity());
+        while !s.is_char_boundary(len) { len -= 1 }
+        // `get()`, as slicing a `str` links in core::fmt's char formatting for the panic message
+        self.0.push_str(s.get(..len).unwrap_or_default());
+        Ok(())
+    }
+}
+
+/// Send a log record, with the format string interned. Use the level macros in log.rs rather than this.
+#[macro_export]
+macro_rules! ilog {
+    ($level:expr, $fmt:literal $(, $arg:expr)* $(,)?) => {
+        $crate::ilog!(@send $level as u8, concat!(module_path!(), "|", $fmt) $(, $arg)*)
+    };
+    // Also used by `print!` and `println!`. `$string` is the whole interned string.
+    (@send $level:expr, $string:expr $(, $arg:expr)*) => {
+        {
+            const STRING: &str = $string;
+            #[link_section = ".ilog"]
+            #[used]
+            static INTERNED: [u8; STRING.len() + 1] = $crate::ilog::nul_terminated(STRING);
+
+            #[allow(unused_imports)]
+            use $crate::ilog::{EncodeDebug as _, EncodeDisplay as _, EncodeValue as _};
+            #[allow(unused_mut)]
+            let mut record = $crate::ilog::Record::new($level, core::ptr::addr_of!(INTERNED) as usize as u16);
+            $( (&&&$crate::ilog::Arg(&$arg)).encode(&mut record); )*
+            record.send();
+        }
+    };
+}
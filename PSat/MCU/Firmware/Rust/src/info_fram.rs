@@ -0,0 +1,77 @@
+// Storage that survives resets and power loss, in the 512 bytes of information FRAM at 0x1800.
+//
+// Information FRAM is write protected (SYSCFG0.DFWP) except while `write()` is running. Each user gets a fixed region,
+// listed here so they can't overlap as more are added:
+//
+// | Offset | Length | Used by                         |
+// |--------|--------|---------------------------------|
+// | 0x000  | 32     | `boot_log.rs`                   |
+// | 0x020  | 160    | `crash_log.rs`                  |
+// | 0x0C0  | 16     | `flight_log.rs` (just a header) |
+// | 0x0D0  | 64     | `config_store.rs`               |
+//
+// FRAM has no erase cycle and practically unlimited write endurance, so it's fine to write a region on every boot.
+
+#![allow(dead_code)]
+use core::mem::{size_of, MaybeUninit};
+use msp430fr2355::SYS;
+
+const START: usize = 0x1800;
+const LEN: usize = 512;
+/// SYSCFG0 ignores writes unless its upper byte is this.
+const SYSCFG0_PASSWORD: u8 = 0xA5;
+
+#[derive(Clone, Copy)]
+pub struct Region {
+    offset: usize,
+    len: usize,
+}
+impl Region {
+    const fn new(offset: usize, len: usize) -> Self {
+        assert!(offset + len <= LEN && offset.is_multiple_of(2));
+        Self { offset, len }
+    }
+
+    fn address<T>(&self) -> usize {
+        assert!(size_of::<T>() <= self.len, "Value doesn't fit in its FRAM region");
+        START + self.offset
+    }
+}
+
+pub const BOOT_LOG: Region = Region::new(0x000, 32);
+pub const CRASH_LOG: Region = Region::new(0x020, 160);
+pub const FLIGHT_LOG: Region = Region::new(0x0C0, 16);
+pub const CONFIG: Region = Region::new(0x0D0, 64);
+
+/// Read a value from the start of `region`.
+///
+/// # Safety
+/// Whatever is in FRAM must be a valid `T`. Never written, it could be any bit pattern, so `T` should be made of plain
+/// integers (no `bool`s, enums or references) and checked with a magic number before use.
+pub unsafe fn read<T: Copy>(region: Region) -> T {
+    let mut value = MaybeUninit::<T>::uninit();
+    unsafe {
+        copy_bytes(region.address::<T>() as *const u8, value.as_mut_ptr() as *mut u8, size_of::<T>());
+        value.assume_init()
+    }
+}
+
+/// Write a value to the start of `region`.
+pub fn write<T: Copy>(region: Region, value: &T) {
+    let address = region.address::<T>();
+    let sys = unsafe { &*SYS::ptr() };
+    msp430::critical_section::with(|_| {
+        sys.syscfg0.modify(|r, w| unsafe { w.bits(r.bits() & 0x00FF).frwppw().bits(SYSCFG0_PASSWORD) }.dfwp().clear_bit());
+        unsafe { copy_bytes(value as *const T as *const u8, address as *mut u8, size_of::<T>()) };
+        sys.syscfg0.modify(|r, w| unsafe { w.bits(r.bits() & 0x00FF).frwppw().bits(SYSCFG0_PASSWORD) }.dfwp().set_bit());
+    });
+}
+
+/// A byte at a time, with volatile accesses so the write can't move outside the unprotected window. A volatile access to
+/// a whole struct is unrolled into an instruction per word, which for the crash log alone is over a kilobyte of flash.
+#[inline(never)]
+unsafe fn copy_bytes(from: *const u8, to: *mut u8, len: usize) {
+    for i in 0..len {
+        unsafe { core::ptr::write_volatile(to.add(i), core::ptr::read_volatile(from.add(i))) };
+    }
+}
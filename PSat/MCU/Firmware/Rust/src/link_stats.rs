@@ -0,0 +1,117 @@
+// Link quality statistics for range testing. Feed in each received packet's sequence number and signal quality,
+// then print a summary to compare antenna setups, locations etc.
+
+#![allow(dead_code)]
+use crate::println;
+
+/// Number of recent packets used for the rolling RSSI/SNR figures.
+const WINDOW_LEN: usize = 32;
+
+/// Upper bounds (inclusive) of the gap histogram buckets, in lost packets. The last bucket catches everything larger.
+const GAP_BUCKETS: [u16; 6] = [1, 2, 4, 8, 16, 64];
+
+pub struct LinkStats {
+    last_seq: Option<u16>,
+    received: u32,
+    lost: u32,
+    /// Number of gaps of each size, see `GAP_BUCKETS`.
+    gap_histogram: [u16; GAP_BUCKETS.len() + 1],
+    rssi: Window<i16>,
+    snr: Window<i8>,
+}
+impl LinkStats {
+    pub fn new() -> Self {
+        Self {
+            last_seq: None,
+            received: 0,
+            lost: 0,
+            gap_histogram: [0; GAP_BUCKETS.len() + 1],
+            rssi: Window::new(),
+            snr: Window::new(),
+        }
+    }
+
+    /// Record a received packet.
+    pub fn record(&mut self, seq: u16, rssi: i16, snr: i8) {
+        if let Some(last) = self.last_seq {
+            let gap = seq.wrapping_sub(last).wrapping_sub(1);
+            if seq == last {
+                return; // Duplicate, ignore
+            }
+            else if gap > u16::MAX / 2 {
+                // Sequence number went backwards, the transmitter must have restarted. Start again.
+                *self = Self::new();
+            }
+            else if gap > 0 {
+                self.lost += gap as u32;
+                let bucket = GAP_BUCKETS.iter().position(|&max| gap <= max).unwrap_or(GAP_BUCKETS.len());
+                self.gap_histogram[bucket] = self.gap_histogram[bucket].saturating_add(1);
+            }
+        }
+        self.last_seq = Some(seq);
+        self.received += 1;
+        self.rssi.push(rssi);
+        self.snr.push(snr);
+    }
+
+    /// Packet error rate in tenths of a percent.
+    pub fn packet_error_rate_per_mille(&self) -> u32 {
+        let total = self.received + self.lost;
+        if total == 0 { 0 } else { (self.lost as u64 * 1000 / total as u64) as u32 }
+    }
+
+    pub fn print_summary(&self) {
+        let per = self.packet_error_rate_per_mille();
+        println!("--- Received: {}, Lost: {}, PER: {}.{}%", self.received, self.lost, per / 10, per % 10);
+
+        if let Some((min, mean, max)) = self.rssi.min_mean_max() {
+            println!("RSSI (last {}): min {}, mean {}, max {}", self.rssi.len(), min, mean, max);
+        }
+        if let Some((min, mean, max)) = self.snr.min_mean_max() {
+            println!("SNR  (last {}): min {}, mean {}, max {}", self.snr.len(), min, mean, max);
+        }
+
+        crate::print!("Gaps:");
+        let mut lower = 1;
+        for (i, count) in self.gap_histogram.iter().enumerate() {
+            match GAP_BUCKETS.get(i) {
+                Some(&upper) if upper == lower => crate::print!(" [{}]: {}", upper, count),
+                Some(&upper) => crate::print!(" [{}-{}]: {}", lower, upper, count),
+                None => crate::print!(" [{}+]: {}", lower, count),
+            }
+            lower = GAP_BUCKETS.get(i).map_or(lower, |&upper| upper + 1);
+        }
+        println!("");
+    }
+}
+
+/// A fixed-size ring buffer of the most recent values.
+struct Window<T> {
+    values: [T; WINDOW_LEN],
+    len: u8,
+    next: u8,
+}
+impl<T: Copy + Default + Ord + Into<i32>> Window<T> {
+    fn new() -> Self {
+        Self { values: [T::default(); WINDOW_LEN], len: 0, next: 0 }
+    }
+
+    fn push(&mut self, value: T) {
+        self.values[self.next as usize] = value;
+        self.next = (self.next + 1) % WINDOW_LEN as u8;
+        self.len = (self.len + 1).min(WINDOW_LEN as u8);
+    }
+
+    fn len(&self) -> u8 {
+        self.len
+    }
+
+    fn min_mean_max(&self) -> Option<(T, i32, T)> {
+        let values = &self.values[..self.len as usize];
+        let min = *values.iter().min()?;
+        let max = *values.iter().max()?;
+        let sum: i32 = values.iter().map(|&v| v.into()).sum();
+        Some((min, sum / self.len as i32, max))
+    }
+}
+
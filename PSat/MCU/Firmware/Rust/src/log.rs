@@ -0,0 +1,177 @@
+// Leveled logging: `error!`, `warn!`, `info!`, `debug!` and `trace!`. They print like `println!`, with the level and
+// module in front:
+//
+//     [W main] Rail V1_8 out of regulation, switched off
+//
+// Levels above the `max-level-*` cargo feature are compiled out, strings and all, so e.g. building with
+// `--features max-level-info` removes every `debug!` and `trace!` from the binary. With no feature, only `error!` is
+// kept: anything more doesn't leave room for the flight log in flash (see memory.x), so it's for the bench.
+// Below that, `set_level()` (or the console's `loglevel` command) can quieten things further at runtime.
+//
+// With the `deferred-log` feature, messages are sent in binary with the strings left in the ELF instead, see ilog.rs.
+
+use core::cell::Cell;
+use msp430::{critical_section, interrupt::Mutex};
+use ufmt::derive::uDebug;
+
+#[derive(Debug, uDebug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+    Trace = 5,
+}
+impl Level {
+    #[cfg(feature = "console")]
+    const ALL: [Level; 5] = [Level::Error, Level::Warn, Level::Info, Level::Debug, Level::Trace];
+
+    #[cfg(not(feature = "deferred-log"))]
+    pub fn tag(self) -> char {
+        match self {
+            Level::Error => 'E',
+            Level::Warn => 'W',
+            Level::Info => 'I',
+            Level::Debug => 'D',
+            Level::Trace => 'T',
+        }
+    }
+
+    /// Parse a level's name, e.g. "warn".
+    #[cfg(feature = "console")]
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|level| level.name() == name)
+    }
+
+    #[cfg(feature = "console")]
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        }
+    }
+}
+
+/// The most verbose level compiled in, from the cargo features. 0 means nothing is logged. If several features are
+/// enabled, the quietest wins.
+pub const STATIC_MAX_LEVEL: u8 =
+    if cfg!(feature = "max-level-off") { 0 }
+    else if cfg!(feature = "max-level-error") { Level::Error as u8 }
+    else if cfg!(feature = "max-level-warn") { Level::Warn as u8 }
+    else if cfg!(feature = "max-level-info") { Level::Info as u8 }
+    else if cfg!(feature = "max-level-debug") { Level::Debug as u8 }
+    else if cfg!(feature = "max-level-trace") { Level::Trace as u8 }
+    else { Level::Error as u8 };
+
+static LEVEL: Mutex<Cell<u8>> = Mutex::new(Cell::new(STATIC_MAX_LEVEL));
+
+/// Whether messages at `level` are compiled in. Constant for a constant `level`, so the macros' dead branches go away.
+// Matching on `STATIC_MAX_LEVEL` rather than comparing with it, as comparing with 0 (`max-level-off`) upsets clippy
+pub const fn compiled_in(level: Level) -> bool {
+    match STATIC_MAX_LEVEL {
+        0 => false,
+        max => level as u8 <= max,
+    }
+}
+
+/// Only log messages at `level` or more important. Can't turn on levels that were compiled out.
+#[cfg(feature = "console")]
+pub fn set_level(level: Option<Level>) {
+    let level = match STATIC_MAX_LEVEL {
+        0 => 0,
+        max => level.map_or(0, |l| l as u8).min(max),
+    };
+    critical_section::with(|cs| LEVEL.borrow(cs).set(level));
+}
+
+/// The level set by `set_level()`, or `None` if logging is off.
+#[cfg(feature = "console")]
+pub fn level() -> Option<Level> {
+    let level = critical_section::with(|cs| LEVEL.borrow(cs).get());
+    Level::ALL.into_iter().find(|l| *l as u8 == level)
+}
+
+/// Whether a message at `level` would be printed right now.
+pub fn enabled(level: Level) -> bool {
+    compiled_in(level) && level as u8 <= critical_section::with(|cs| LEVEL.borrow(cs).get())
+}
+
+/// `module_path!()` without the crate name, which is the same for every message. A `const fn`, so the macros work it out
+/// at compile time rather than searching the path on every message.
+#[cfg(not(feature = "deferred-log"))]
+pub const fn module_name(path: &str) -> &str {
+    let bytes = path.as_bytes();
+    let mut i = 0;
+    while i + 1 < bytes.len() {
+        if bytes[i] == b':' && bytes[i + 1] == b':' {
+            let (_, module) = bytes.split_at(i + 2);
+            return match core::str::from_utf8(module) { Ok(module) => module, Err(_) => path };
+        }
+        i += 1;
+    }
+    "main"
+}
+
+/// Log at `level`. Prefer the macros for each level.
+#[cfg(not(feature = "deferred-log"))]
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)+) => {
+        {
+            let level: $crate::log::Level = $level;
+            // The first half is constant, so disabled levels are compiled out
+            if $crate::log::compiled_in(level) && $crate::log::enabled(level) {
+                const MODULE: &str = $crate::log::module_name(module_path!());
+                $crate::print!("[{} {}] ", level.tag(), MODULE);
+                $crate::println!($($arg)+);
+            }
+        }
+    };
+}
+
+/// Log at `level`. Prefer the macros for each level.
+#[cfg(feature = "deferred-log")]
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)+) => {
+        {
+            let level: $crate::log::Level = $level;
+            if $crate::log::compiled_in(level) && $crate::log::enabled(level) {
+                $crate::ilog!(level, $($arg)+);
+            }
+        }
+    };
+}
+
+/// Something has gone wrong and can't be worked around.
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)+) => { $crate::log!($crate::log::Level::Error, $($arg)+) };
+}
+
+/// Something has gone wrong, but we carried on.
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)+) => { $crate::log!($crate::log::Level::Warn, $($arg)+) };
+}
+
+/// Normal operation worth knowing about.
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)+) => { $crate::log!($crate::log::Level::Info, $($arg)+) };
+}
+
+/// Detail for debugging.
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)+) => { $crate::log!($crate::log::Level::Debug, $($arg)+) };
+}
+
+/// Very detailed or very frequent.
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)+) => { $crate::log!($crate::log::Level::Trace, $($arg)+) };
+}
@@ -2,46 +2,168 @@
 use core::{cell::RefCell, time::Duration};
 
 use embedded_hal_bus::spi::RefCellDevice;
-use embedded_lora_rfm95::{error::{IoError, RxCompleteError, TxStartError}, lora::types::{Bandwidth, CodingRate, CrcMode, HeaderMode, Polarity, PreambleLength, SpreadingFactor, SyncWord}, rfm95::{self, Rfm95Driver}};
-use embedded_hal_compat::{eh1_0::delay::DelayNs, markers::ForwardOutputPin, Forward, ForwardCompat};
+use embedded_lora_rfm95::{error::{IoError, RxCompleteError, RxStartError, TxStartError}, lora::types::{Bandwidth, CodingRate, CrcMode, Frequency, HeaderMode, Polarity, PreambleLength, SpreadingFactor, SyncWord}, lora::airtime::{airtime, symbol_airtime}, lora::config::Config, rfm95::{self, Rfm95Driver}};
+use embedded_hal_compat::{eh1_0::{delay::DelayNs, digital::{ErrorType, OutputPin}, spi::SpiDevice}, markers::ForwardOutputPin, Forward, ForwardCompat};
+use static_cell::StaticCell;
 use msp430fr2x5x_hal::delay::Delay;
 use nb::Error::{WouldBlock, Other};
-use crate::{board::FwSpiBus, pin_mappings::{RadioCsPin, RadioResetPin}};
+use crate::{adr::{self, LinkProfile}, board::FwSpiBus, config_store, pin_mappings::{RadioCsPin, RadioResetPin}, protocol::{Ack, Frame, FrameHeader, FrameKind, DEFAULT_TTL}};
 
-const LORA_FREQ_HZ: u32 = 915_000_000;
 pub use rfm95::RFM95_FIFO_SIZE;
 
-pub fn new(spi_ref: &'static RefCell<FwSpiBus>, cs_pin: RadioCsPin, reset_pin: RadioResetPin, delay: Delay) -> Radio {
-    let radio_spi: SPIDevice = RefCellDevice::new(spi_ref, cs_pin.forward(), crate::lora::DelayWrapper(delay)).unwrap();
-    let mut rfm95 = match Rfm95Driver::new(radio_spi, reset_pin.forward(), &mut DelayWrapper(delay)) {
-        Ok(rfm) => rfm,
-        Err(_e) => panic!("Radio reports invalid silicon revision. Is the beacon connected?"),
-    };
+/// The radio didn't respond with the expected silicon revision. Usually because the beacon board isn't connected.
+#[derive(Debug)]
+pub struct RadioNotFound;
+
+pub fn new(spi_ref: &'static RefCell<FwSpiBus>, cs_pin: RadioCsPin, reset_pin: RadioResetPin, delay: Delay) -> Result<Radio, RadioNotFound> {
+    // The driver doesn't expose every register we need, so we keep a second handle to the radio for raw register access.
+    // Both handles need the chip select pin, so it gets shared the same way as the SPI bus.
+    static CS: StaticCell<RefCell<FwCsPin>> = StaticCell::new();
+    let cs_ref: &'static _ = CS.init(RefCell::new(cs_pin.forward()));
+
+    let radio_spi: SPIDevice = RefCellDevice::new(spi_ref, SharedCsPin(cs_ref), crate::lora::DelayWrapper(delay)).unwrap();
+    let registers: SPIDevice = RefCellDevice::new(spi_ref, SharedCsPin(cs_ref), crate::lora::DelayWrapper(delay)).unwrap();
+    let config = config_store::get();
+    let mut rfm95 = Rfm95Driver::new(radio_spi, reset_pin.forward(), &mut DelayWrapper(delay)).map_err(|_| RadioNotFound)?;
     
     // 62.5kHz bandwidth, 4/5 coding rate, SF10 gives a bitrate of about 500bps.
     let lora_config = embedded_lora_rfm95::lora::config::Builder::builder()
         .set_bandwidth(Bandwidth::B62_5) // lower bandwidth == longer range, but very low bandwidths can suffer from clock source tolerance issues
         .set_coding_rate(CodingRate::C4_5) // Error correction lowers bitrate. Consider how electronically noisy the area might be.
         .set_crc_mode(CrcMode::Disabled)
-        .set_frequency(LORA_FREQ_HZ.into())
+        .set_frequency(config.frequency_hz.into())
         .set_header_mode(HeaderMode::Explicit)
         .set_polarity(Polarity::Normal)
         .set_preamble_length(PreambleLength::L8)
         .set_spreading_factor(SpreadingFactor::S10) // High SF == Best range
-        .set_sync_word(SyncWord::PRIVATE);
+        .set_sync_word(SyncWord::new(config.sync_word));
     rfm95.set_config(&lora_config).unwrap();
 
-    Radio{driver: rfm95}
+    let mut radio = Radio{driver: rfm95, registers, address: config.payload_id, tx_seq: 0};
+    radio.set_profile(&adr::PROFILES[config.link_profile as usize]).unwrap();
+    Ok(radio)
 }
 
 type FwCsPin = Forward<RadioCsPin, ForwardOutputPin>;
-type SPIDevice = RefCellDevice<'static, FwSpiBus, FwCsPin, DelayWrapper>;
+type SPIDevice = RefCellDevice<'static, FwSpiBus, SharedCsPin, DelayWrapper>;
 type RFM95 = Rfm95Driver<SPIDevice>;
 /// Top-level interface for the radio module.
 pub struct Radio {
     pub driver: RFM95,
+    /// Raw register access for features the driver doesn't support. See `read_register()`.
+    registers: SPIDevice,
+    /// Our address. Used as the source of outgoing frames and to filter incoming ones.
+    address: u8,
+    tx_seq: u8,
 }
 impl Radio {
+    pub fn address(&self) -> u8 {
+        self.address
+    }
+
+    /// Change our address, e.g. to act as the ground station.
+    pub fn set_address(&mut self, address: u8) {
+        self.address = address;
+    }
+
+    /// Read a radio register directly, bypassing the driver. Register addresses are listed in the RFM95 datasheet.
+    pub fn read_register(&mut self, address: u8) -> Result<u8, RegisterError> {
+        let mut command = [address & 0x7F, 0];
+        self.registers.transfer_in_place(&mut command).map_err(|_| RegisterError)?;
+        Ok(command[1])
+    }
+
+    /// Write a radio register directly, bypassing the driver. Be careful not to change settings the driver relies on.
+    pub fn write_register(&mut self, address: u8, value: u8) -> Result<(), RegisterError> {
+        let mut command = [address | 0x80, value];
+        self.registers.transfer_in_place(&mut command).map_err(|_| RegisterError)
+    }
+
+    /// Switch the radio's operating mode. Starting TX or RX is best left to the `transmit_*()` and `recieve_*()` functions.
+    pub fn set_mode(&mut self, mode: RadioMode) -> Result<(), RegisterError> {
+        let op_mode = self.read_register(REG_OP_MODE)?;
+        self.write_register(REG_OP_MODE, (op_mode & !0b111) | mode as u8)
+    }
+
+    /// The RSSI currently seen by the radio in dBm, in the channel bandwidth. Only meaningful in `RadioMode::RxContinuous`.
+    pub fn current_rssi(&mut self) -> Result<i16, RegisterError> {
+        let op_mode = self.read_register(REG_OP_MODE)?;
+        let low_frequency_mode = op_mode & (1 << 3) != 0;
+        let offset = if low_frequency_mode { -164 } else { -157 };
+        Ok(self.read_register(REG_RSSI_VALUE)? as i16 + offset)
+    }
+
+    /// Set the transmit power, in dBm. Values outside 2-17dBm are clamped.
+    pub fn set_tx_power(&mut self, dbm: u8) -> Result<(), RegisterError> {
+        // PA_BOOST output (the only one connected on the RFM95), Pout = 2 + OutputPower
+        let output_power = dbm.clamp(2, 17) - 2;
+        self.write_register(REG_PA_CONFIG, 0b1111_0000 | output_power)
+    }
+
+    /// Switch to one of the link profiles used by adaptive data rate. Only call this while the radio is idle.
+    pub fn set_profile(&mut self, profile: &LinkProfile) -> Result<(), RegisterError> {
+        self.driver.set_spreading_factor(profile.spreading_factor).map_err(|_| RegisterError)?;
+        self.set_tx_power(profile.tx_power_dbm)
+    }
+
+    /// How long a packet of `len` bytes takes to send with the current settings.
+    pub fn airtime(&mut self, len: usize) -> Result<Duration, IoError> {
+        let driver = &mut self.driver;
+        let config = Config::builder()
+            .set_spreading_factor(driver.spreading_factor()?)
+            .set_bandwidth(driver.bandwidth()?)
+            .set_coding_rate(driver.coding_rate()?)
+            .set_polarity(driver.polarity()?)
+            .set_header_mode(driver.header_mode()?)
+            .set_crc_mode(driver.crc_mode()?)
+            .set_sync_word(driver.sync_word()?)
+            .set_preamble_length(driver.preamble_len()?)
+            .set_frequency(driver.frequency()?);
+        Ok(airtime(len, config))
+    }
+
+    /// How long to listen for a short reply (such as an ACK) to a frame we've just sent, at the current link profile.
+    pub fn reply_timeout(&mut self) -> Duration {
+        const REPLY_SYMBOLS: u32 = 64; // Preamble, header and a few bytes of payload, plus time for the other end to turn around.
+        let spreading_factor = self.driver.spreading_factor().unwrap_or(SpreadingFactor::S12);
+        let bandwidth = self.driver.bandwidth().unwrap_or(Bandwidth::B62_5);
+        symbol_airtime(spreading_factor, bandwidth) * REPLY_SYMBOLS
+    }
+
+    /// Listen for the ACK of frame `seq`, sent by `from`. Blocks for up to `reply_timeout()`.
+    pub fn wait_for_ack(&mut self, from: u8, seq: u8) -> Option<Ack> {
+        let mut buf = [0u8; RFM95_FIFO_SIZE];
+        let timeout = self.reply_timeout();
+        self.recieve_start(Some(timeout)).ok()?;
+
+        let msg = match nb::block!(self.recieve_is_complete(&mut buf)) {
+            Ok(msg) => msg,
+            Err(_) => return None, // Timed out
+        };
+        let frame = Frame::parse(msg).ok()?;
+        let is_our_ack = frame.header.kind == FrameKind::Ack && frame.header.src == from && frame.header.is_for(self.address);
+        if !is_our_ack { return None }
+
+        Ack::from_bytes(frame.payload).ok().filter(|ack| ack.acked_seq == seq)
+    }
+
+    /// Retune the radio. Only call this while the radio is idle (i.e. not mid-transmission or listening).
+    pub fn set_frequency(&mut self, freq_hz: u32) -> Result<(), IoError> {
+        self.driver.set_frequency(Frequency::hz(freq_hz))
+    }
+
+    /// Begin transmission of a frame addressed to `dest` and return immediately. Check whether the transmission is complete by calling `transmit_is_complete()`.
+    /// 
+    /// Returns the sequence number the frame was sent with.
+    pub fn transmit_frame_start(&mut self, dest: u8, kind: FrameKind, payload: &[u8]) -> Result<u8, TxError> {
+        let header = FrameHeader { dest, src: self.address, kind, seq: self.tx_seq, ttl: DEFAULT_TTL };
+        let frame = Frame::encode(&header, payload).map_err(|_| TxError::InvalidBufferSize)?;
+        self.tx_seq = self.tx_seq.wrapping_add(1);
+        self.transmit_start(&frame)?;
+        Ok(header.seq)
+    }
+
+
     /// Begin transmission and return immediately. Check whether the transmission is complete by calling `transmit_is_complete()`.
     pub fn transmit_start(&mut self, data: &[u8]) -> Result<(), TxError>{
         match self.driver.start_tx(data) {
@@ -62,12 +184,11 @@ impl Radio {
     /// Tell the radio to listen for a packet and return immediately. Check whether anything was recieved by calling `recieve_is_complete()`.
     /// 
     /// A timeout value is optional, if none is provided the maximum timeout is used. You should prepare to deal with timeouts.
-    pub fn recieve_start(&mut self, timeout: Option<Duration>) {
-        let timeout = match timeout {
-            Some(t) => t,
-            None => self.driver.rx_timeout_max().unwrap(),
-        };
-        self.driver.start_rx(timeout).unwrap();
+    /// The radio can only time out after 1023 symbols, so longer timeouts are shortened to `rx_timeout_max()`: about 2
+    /// seconds at SF7, 62.5kHz.
+    pub fn recieve_start(&mut self, timeout: Option<Duration>) -> Result<(), RxStartError> {
+        let max = self.driver.rx_timeout_max()?;
+        self.driver.start_rx(timeout.map_or(max, |t| t.min(max)))
     }
 
     /// Check whether the radio has recieved a packet. If so, returns a reference to the slice of buf that contains the message.
@@ -80,8 +201,39 @@ impl Radio {
             Err(e) => Err(Other(e)),
         }
     }
+
+    /// Check whether the radio has recieved a frame addressed to us (or broadcast). 
+    /// 
+    /// Malformed frames and frames addressed to other radios are discarded and listening restarts automatically,
+    /// so from the caller's point of view these look like `WouldBlock`.
+    pub fn recieve_frame_is_complete<'a>(&mut self, buf: &'a mut [u8; rfm95::RFM95_FIFO_SIZE]) -> nb::Result<Frame<'a>, RxCompleteError> {
+        let msg = self.recieve_is_complete(buf)?;
+        match Frame::parse(msg) {
+            Ok(frame) if frame.header.is_for(self.address) => Ok(frame),
+            _ => {
+                self.recieve_start(None).unwrap();
+                Err(WouldBlock)
+            }
+        }
+    }
 }
 
+const REG_OP_MODE: u8 = 0x01;
+const REG_PA_CONFIG: u8 = 0x09;
+const REG_RSSI_VALUE: u8 = 0x1B;
+
+/// Operating modes, as written into RegOpMode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum RadioMode {
+    Sleep = 0b000,
+    Standby = 0b001,
+    RxContinuous = 0b101,
+}
+
+#[derive(Debug)]
+pub struct RegisterError;
+
 #[derive(Debug)]
 pub enum RxError {
     CrcFailure,
@@ -95,6 +247,23 @@ pub enum TxError {
     IoError,
 }
 
+/// A chip select pin that can be handed to more than one `RefCellDevice`.
+/// 
+/// Safe for the same reason sharing the bus is: we're single threaded and never use the radio from an interrupt, 
+/// so only one device is ever mid-transaction.
+pub struct SharedCsPin(&'static RefCell<FwCsPin>);
+impl ErrorType for SharedCsPin {
+    type Error = <FwCsPin as ErrorType>::Error;
+}
+impl OutputPin for SharedCsPin {
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        self.0.borrow_mut().set_low()
+    }
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        self.0.borrow_mut().set_high()
+    }
+}
+
 use embedded_hal::blocking::delay::DelayMs;
 // The radio library uses a different version of embedded_hal, so we need to write some wrappers.
 pub struct DelayWrapper(Delay);
@@ -123,57 +292,59 @@ impl DelayNs for DelayWrapper {
 pub mod tests {
     use embedded_hal::timer::CountDown;
     use embedded_lora_rfm95::error::RxCompleteError;
-    use ufmt::uwrite;
+    use crate::{link_stats::LinkStats, protocol::{FrameKind, RangeTest, BROADCAST_ID}};
+
+    /// Print a link quality summary this often during `range_test_rx()`.
+    const SUMMARY_INTERVAL_S: u8 = 10;
 
     pub fn range_test_tx(mut board: crate::board::Board) -> ! {
-        let mut current_time = Time::default();
+        let mut packet = RangeTest { seq: 0, tx_time_s: 0 };
         board.timer_b0.start(msp430fr2x5x_hal::clock::REFOCLK); // 1 second timer
-        board.radio.transmit_start(&time_to_bytes(&current_time)).unwrap();
+        board.radio.transmit_frame_start(BROADCAST_ID, FrameKind::RangeTest, &packet.to_bytes()).unwrap();
         loop {
             // Sends at most one message per second.
             if board.timer_b0.wait().is_ok() {
-                current_time.increment();
+                packet.tx_time_s += 1;
                 
                 if board.radio.transmit_is_complete().is_ok() {
                     board.gpio.green_led.toggle();
-                    board.radio.transmit_start(&time_to_bytes(&current_time)).unwrap();
+                    packet.seq = packet.seq.wrapping_add(1);
+                    board.radio.transmit_frame_start(BROADCAST_ID, FrameKind::RangeTest, &packet.to_bytes()).unwrap();
                 }
             }
         }
     }
 
-    fn time_to_bytes(time: &Time) -> [u8;8] {
-        [
-            time.hours / 10 + b'0', 
-            time.hours % 10 + b'0', 
-            b':', 
-            time.minutes / 10 + b'0', 
-            time.minutes % 10 + b'0', 
-            b':', 
-            time.seconds / 10 + b'0', 
-            time.seconds % 10 + b'0'
-        ]
-    }
-
     pub fn range_test_rx(mut board: crate::board::Board) -> ! {
         let mut buf = [0u8; super::RFM95_FIFO_SIZE];
         let mut current_time = Time::default();
+        let mut stats = LinkStats::new();
+        let mut seconds_since_summary = 0;
         board.timer_b0.start(msp430fr2x5x_hal::clock::REFOCLK); // 1 second timer
-        board.radio.recieve_start(None);
+        board.radio.recieve_start(None).unwrap();
         loop {
-            match board.radio.recieve_is_complete(&mut buf) {
-                Err(nb::Error::Other(RxCompleteError::TimeoutError(_))) => board.radio.recieve_start(None),
+            match board.radio.recieve_frame_is_complete(&mut buf) {
+                Err(nb::Error::Other(RxCompleteError::TimeoutError(_))) => board.radio.recieve_start(None).unwrap(),
                 Err(_e) => (),
-                Ok(msg) => {
+                Ok(frame) => {
+                    board.radio.recieve_start(None).unwrap();
+                    if frame.header.kind != FrameKind::RangeTest { continue }
+                    let Ok(packet) = RangeTest::from_bytes(frame.payload) else {continue};
                     let Ok(signal_strength) = board.radio.driver.get_packet_strength() else {continue};
                     let Ok(rssi) = board.radio.driver.get_packet_rssi() else {continue};
                     let Ok(snr) = board.radio.driver.get_packet_snr() else {continue};
-                    crate::println!("[{}] '{}', Strength: {}, RSSI: {}, SNR: {}", current_time, core::str::from_utf8(msg).unwrap(), signal_strength, rssi, snr);
-                    board.radio.recieve_start(None);
+                    stats.record(packet.seq, rssi, snr);
+                    crate::println!("[{}] #{} sent at {}, Strength: {}, RSSI: {}, SNR: {}", 
+                        current_time, packet.seq, Time::from_seconds(packet.tx_time_s), signal_strength, rssi, snr);
                 },
             }
             if board.timer_b0.wait().is_ok() {
                 current_time.increment();
+                seconds_since_summary += 1;
+                if seconds_since_summary >= SUMMARY_INTERVAL_S {
+                    seconds_since_summary = 0;
+                    stats.print_summary();
+                }
             }
         }
     }
@@ -184,6 +355,14 @@ pub mod tests {
         hours: u8,
     }
     impl Time {
+        fn from_seconds(seconds: u32) -> Self {
+            Time {
+                seconds: (seconds % 60) as u8,
+                minutes: (seconds / 60 % 60) as u8,
+                hours: (seconds / 3600) as u8,
+            }
+        }
+
         /// Add one second to the time.
         pub fn increment(&mut self) {
             if self.seconds < 59 {
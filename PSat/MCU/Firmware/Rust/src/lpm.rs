@@ -0,0 +1,291 @@
+// Low power modes and a tickless clock.
+//
+// Timer_B0 runs continuously from ACLK (32768 Hz), which keeps going in LPM3. Its overflows extend the count to 32 bits,
+// and its CCR0 is used as an alarm to wake us at the next due event. Between events the CPU sleeps in:
+// - LPM0: CPU off, SMCLK still running. Needed to wake on bytes from the GPS, as its UART runs from SMCLK.
+// - LPM3: Only ACLK running. Much lower power, but only the timer can wake us.
+//
+// The radio's IRQ line is on P5.3, which (unlike P1-P4) can't generate interrupts on the FR2355. Instead, when asked to
+// wake for the radio we never sleep for longer than `RADIO_POLL_MS`. The radio holds a received packet in its FIFO, so
+// nothing is lost, it just arrives a little later.
+//
+// Once `start_clock()` has been called, Timer_B0 belongs to this module. Don't also use `board.timer_b0` directly.
+//
+// From then on interrupts stay enabled while tasks run, not just while sleeping. Otherwise a task that blocks for more
+// than one overflow (2 seconds), like a beacon at SF12 waiting for its ACK, loses 2 seconds from `now()` for each extra
+// one. The only other interrupts enabled outside sleep are the debug UART's (see serial.rs); the wake sources are only
+// armed while sleeping.
+
+use core::cell::Cell;
+use msp430::{critical_section, interrupt::Mutex};
+use msp430fr2355::{interrupt, E_USCI_A1, TB0};
+use msp430fr2x5x_hal::timer::Timer;
+use crate::println;
+
+pub const TICKS_PER_SECOND: u32 = 32768;
+/// Longest sleep when waiting for the radio.
+const RADIO_POLL_MS: u32 = 50;
+/// Don't bother sleeping for less than this, it isn't worth the wakeup time.
+const MIN_SLEEP_TICKS: u32 = 4;
+
+/// Approximate MCU supply currents at 8 MHz and 3V, from the datasheet. Used by `print_current_estimate()`.
+const ACTIVE_UA: u32 = 2500;
+const LPM0_UA: u32 = 700;
+const LPM3_UA: u32 = 2;
+
+// Status register bits
+const GIE: u16 = 0x08;
+const CPUOFF: u16 = 0x10;
+const SCG0: u16 = 0x40;
+const SCG1: u16 = 0x80;
+
+// Bits for WOKEN_BY
+const WOKEN_BY_ALARM: u8 = 1 << 0;
+const WOKEN_BY_GPS: u8 = 1 << 1;
+
+/// Timer_B0 overflows since `start_clock()`, i.e. the top 16 bits of `now()`.
+static EPOCH: Mutex<Cell<u16>> = Mutex::new(Cell::new(0));
+/// Which interrupts have woken us since the last sleep.
+static WOKEN_BY: Mutex<Cell<u8>> = Mutex::new(Cell::new(0));
+static STATS: Mutex<Cell<SleepStats>> = Mutex::new(Cell::new(SleepStats { since: 0, lpm0_ticks: 0, lpm3_ticks: 0 }));
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum SleepMode {
+    Lpm0,
+    Lpm3,
+}
+
+/// What, other than the deadline, may end a sleep.
+#[derive(Clone, Copy)]
+pub struct WakeSources {
+    /// A byte arriving from the GPS. Only works in LPM0.
+    pub gps_uart: bool,
+    /// Wake every `RADIO_POLL_MS` to check the radio.
+    pub radio: bool,
+}
+impl WakeSources {
+    pub const NONE: Self = Self { gps_uart: false, radio: false };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WakeReason {
+    Deadline,
+    Gps,
+    /// Time to check the radio.
+    Radio,
+}
+
+/// Start the clock, if it isn't already running. Takes the timer to show that we're now using it.
+pub fn start_clock(_timer: &mut Timer<TB0>) {
+    let tb0 = unsafe { &*TB0::ptr() };
+    let ctl = tb0.tb0ctl.read();
+    if ctl.mc().is_continuous() && ctl.tbie().bit_is_set() { return }
+    unsafe { msp430::interrupt::enable() }; // To count overflows, see the top of this file
+
+    critical_section::with(|cs| EPOCH.borrow(cs).set(0));
+    tb0.tb0cctl0.write(|w| w); // Alarm off
+    tb0.tb0ctl.write(|w| w.tbssel().aclk().mc().continuous().tbclr().set_bit().tbie().set_bit());
+    reset_stats();
+    crate::serial::enable_tx_buffering(); // Now that interrupts are on, the TX interrupt can send what's printed
+}
+
+/// Ticks (1/32768 s) since `start_clock()`. Wraps after about 36 hours, so compare times with `wrapping_sub()`.
+pub fn now() -> u32 {
+    let tb0 = unsafe { &*TB0::ptr() };
+    critical_section::with(|cs| {
+        let mut epoch = EPOCH.borrow(cs).get();
+        let mut count = read_counter(tb0);
+        // An overflow that the interrupt hasn't counted yet
+        if tb0.tb0ctl.read().tbifg().bit_is_set() {
+            epoch = epoch.wrapping_add(1);
+            count = read_counter(tb0);
+        }
+        (epoch as u32) << 16 | count as u32
+    })
+}
+
+pub fn ms_to_ticks(ms: u32) -> u32 {
+    (ms as u64 * TICKS_PER_SECOND as u64 / 1000) as u32
+}
+
+pub fn ticks_to_ms(ticks: u32) -> u32 {
+    (ticks as u64 * 1000 / TICKS_PER_SECOND as u64) as u32
+}
+
+/// Whether `time` (from `now()`) has been reached.
+pub fn is_due(time: u32) -> bool {
+    now().wrapping_sub(time) < u32::MAX / 2
+}
+
+/// Sleep until `deadline` (from `now()`) or until one of the `wake` sources needs attention.
+pub fn sleep_until(deadline: u32, mode: SleepMode, wake: WakeSources) -> WakeReason {
+    let reason = sleep(deadline, mode, wake);
+    unsafe { msp430::interrupt::enable() }; // To count overflows while tasks run, see the top of this file
+    reason
+}
+
+fn sleep(deadline: u32, mode: SleepMode, wake: WakeSources) -> WakeReason {
+    let tb0 = unsafe { &*TB0::ptr() };
+    let uca1 = unsafe { &*E_USCI_A1::ptr() };
+    loop {
+        msp430::interrupt::disable();
+        let start = now();
+        let remaining = deadline.wrapping_sub(start);
+        if !(MIN_SLEEP_TICKS..=u32::MAX / 2).contains(&remaining) {
+            return WakeReason::Deadline;
+        }
+        let radio_poll = wake.radio && remaining > ms_to_ticks(RADIO_POLL_MS);
+        let alarm = if radio_poll { start.wrapping_add(ms_to_ticks(RADIO_POLL_MS)) } else { deadline };
+
+        if mode == SleepMode::Lpm3 {
+            crate::serial::flush(); // The debug UART runs from SMCLK, so it can't finish sending in LPM3
+        }
+
+        // Arm the wake sources. Interrupts are off, so anything that's already pending fires as soon as we sleep.
+        critical_section::with(|cs| WOKEN_BY.borrow(cs).set(0));
+        tb0.tb0ccr0.write(|w| unsafe { w.bits(alarm as u16) });
+        tb0.tb0cctl0.write(|w| w.ccie().set_bit());
+        if wake.gps_uart {
+            uca1.uca1ie().modify(|_, w| w.ucrxie().set_bit());
+        }
+
+        // Setting GIE and the low power bits in one instruction means no interrupt can sneak in between the two
+        match mode {
+            SleepMode::Lpm0 => unsafe { core::arch::asm!("bis.w #{sr}, r2", "nop", sr = const GIE | CPUOFF) },
+            SleepMode::Lpm3 => unsafe { core::arch::asm!("bis.w #{sr}, r2", "nop", sr = const GIE | CPUOFF | SCG0 | SCG1) },
+        }
+        msp430::interrupt::disable();
+
+        tb0.tb0cctl0.write(|w| w);
+        uca1.uca1ie().modify(|_, w| w.ucrxie().clear_bit());
+        record_sleep(mode, now().wrapping_sub(start));
+
+        let woken_by = critical_section::with(|cs| WOKEN_BY.borrow(cs).get());
+        if woken_by & WOKEN_BY_GPS != 0 {
+            return WakeReason::Gps;
+        }
+        if radio_poll && woken_by & WOKEN_BY_ALARM != 0 {
+            return WakeReason::Radio;
+        }
+        // Otherwise the alarm was for the deadline, or fired early because it's more than one overflow away. Check again.
+    }
+}
+
+/// Sleep for `ms` milliseconds, with nothing else able to wake us.
+pub fn sleep_ms(ms: u32, mode: SleepMode) {
+    sleep_until(now().wrapping_add(ms_to_ticks(ms)), mode, WakeSources::NONE);
+}
+
+/// Something that should happen every `period`. The tickless main loop sleeps until the earliest `due_at()`.
+pub struct Periodic {
+    period: u32,
+    next: u32,
+}
+impl Periodic {
+    pub fn every_ms(ms: u32) -> Self {
+        let period = ms_to_ticks(ms);
+        Self { period, next: now().wrapping_add(period) }
+    }
+
+    /// Returns `true` once per period.
+    pub fn poll(&mut self) -> bool {
+        if !is_due(self.next) { return false }
+
+        self.next = self.next.wrapping_add(self.period);
+        if is_due(self.next) {
+            // We fell more than a whole period behind. Don't try to catch up.
+            self.next = now().wrapping_add(self.period);
+        }
+        true
+    }
+
+    pub fn due_at(&self) -> u32 {
+        self.next
+    }
+}
+
+/// Whichever of two times (from `now()`) comes first.
+pub fn earliest(a: u32, b: u32) -> u32 {
+    let now = now();
+    if (a.wrapping_sub(now) as i32) <= (b.wrapping_sub(now) as i32) { a } else { b }
+}
+
+/// Time spent in each mode since the last `reset_stats()`.
+#[derive(Clone, Copy)]
+pub struct SleepStats {
+    since: u32,
+    lpm0_ticks: u32,
+    lpm3_ticks: u32,
+}
+
+pub fn reset_stats() {
+    let since = now();
+    critical_section::with(|cs| STATS.borrow(cs).set(SleepStats { since, lpm0_ticks: 0, lpm3_ticks: 0 }));
+}
+
+/// Print the fraction of time spent in each mode, and the resulting average MCU current. Peripherals aren't included.
+pub fn print_current_estimate() {
+    let stats = critical_section::with(|cs| STATS.borrow(cs).get());
+    let total = now().wrapping_sub(stats.since).max(1);
+    let active = total.saturating_sub(stats.lpm0_ticks + stats.lpm3_ticks);
+
+    let weighted = active as u64 * ACTIVE_UA as u64 + stats.lpm0_ticks as u64 * LPM0_UA as u64 + stats.lpm3_ticks as u64 * LPM3_UA as u64;
+    let average_ua = (weighted / total as u64) as u32;
+    let percent = |ticks: u32| (ticks as u64 * 100 / total as u64) as u32;
+    println!("Over {}ms: active {}%, LPM0 {}%, LPM3 {}%, est. MCU current {}uA",
+        ticks_to_ms(total), percent(active), percent(stats.lpm0_ticks), percent(stats.lpm3_ticks), average_ua);
+}
+
+fn record_sleep(mode: SleepMode, ticks: u32) {
+    critical_section::with(|cs| {
+        let cell = STATS.borrow(cs);
+        let mut stats = cell.get();
+        match mode {
+            SleepMode::Lpm0 => stats.lpm0_ticks = stats.lpm0_ticks.wrapping_add(ticks),
+            SleepMode::Lpm3 => stats.lpm3_ticks = stats.lpm3_ticks.wrapping_add(ticks),
+        }
+        cell.set(stats);
+    });
+}
+
+/// The timer runs from ACLK, which isn't synchronised to MCLK. Read until we get the same value twice.
+fn read_counter(tb0: &msp430fr2355::tb0::RegisterBlock) -> u16 {
+    let mut count = tb0.tb0r.read().bits();
+    loop {
+        let again = tb0.tb0r.read().bits();
+        if again == count { return count }
+        count = again;
+    }
+}
+
+fn set_woken_by(bit: u8) {
+    critical_section::with(|cs| {
+        let cell = WOKEN_BY.borrow(cs);
+        cell.set(cell.get() | bit);
+    });
+}
+
+#[interrupt(wake_cpu)]
+fn TIMER0_B0() {
+    // CCR0 alarm. The flag clears itself.
+    set_woken_by(WOKEN_BY_ALARM);
+}
+
+#[interrupt]
+fn TIMER0_B1() {
+    // Overflow. Doesn't need to wake us. Reading TB0IV clears the flag.
+    const OVERFLOW: u16 = 0x0E;
+    if unsafe { &*TB0::ptr() }.tb0iv.read().bits() == OVERFLOW {
+        critical_section::with(|cs| {
+            let epoch = EPOCH.borrow(cs);
+            epoch.set(epoch.get().wrapping_add(1));
+        });
+    }
+}
+
+#[interrupt(wake_cpu)]
+fn EUSCI_A1() {
+    // Leave the byte for `Gps` to read, just stop the interrupt firing again until the next sleep
+    unsafe { &*E_USCI_A1::ptr() }.uca1ie().modify(|_, w| w.ucrxie().clear_bit());
+    set_woken_by(WOKEN_BY_GPS);
+}
@@ -1,11 +1,13 @@
 #![no_main]
 #![no_std]
+#![feature(abi_msp430_interrupt)]
+#![feature(asm_experimental_arch)]
 
 use arrayvec::ArrayString;
-use gps::GgaParseError;
+use gps::{GgaMessage, GgaParseError};
 // External imports
 use msp430_rt::entry;
-use msp430fr2x5x_hal::hal::blocking::delay::DelayMs;
+use psat_link::hopping::HopSequence;
 
 // Internal modules
 mod pin_mappings { include!("pin_mappings_v2_0.rs"); } // Import 'pin_mappings_v2_0' as 'pin_mappings'
@@ -14,9 +16,49 @@ mod serial;
 mod panic_handler;
 mod lora;
 mod gps;
+mod config;
+mod protocol;
+mod tdma;
+mod ground_station;
+mod survey;
+mod link_stats;
+mod adr;
+mod relay;
+mod aprs;
+mod battery;
+mod power_rails;
+mod lpm;
+mod scheduler;
+mod watchdog;
+mod reset_cause;
+mod info_fram;
+mod boot_log;
+mod crash_log;
+mod blink_code;
+mod status_led;
+mod flight_log;
+mod config_store;
+#[cfg(feature = "console")]
+mod console;
+mod log;
+#[cfg(feature = "deferred-log")]
+mod ilog;
 
 // Internal imports
-use board::Board;
+use battery::BatteryLevel;
+use blink_code::BlinkCode;
+use board::{Board, PowerStage};
+use config::BeaconFormat;
+#[cfg(feature = "console")]
+use console::Console;
+use adr::AdrController;
+use flight_log::FlightLog;
+use lpm::{SleepMode, WakeReason, WakeSources};
+use protocol::{Beacon, Frame, FrameKind, GROUND_STATION_ID};
+use relay::Relay;
+use scheduler::{Event, Scheduler, Signals};
+use status_led::{Activity, StatusLed};
+use watchdog::CheckIn;
 
 #[entry]
 fn main() -> ! {
@@ -25,48 +67,276 @@ fn main() -> ! {
     // Printing can be expensive in terms of executable size. We only have 32kB on the MSP430, use it sparingly.
     // Prints over eUSCI A0. See board::configure() for details.
     println!("Hello world!");
-
-    let mut buf = ArrayString::new();
-    loop {
-
-        match nb::block!(board.gps.get_gga_message(&mut buf)) {
-            Ok(results) => {
-                println!("Time: {}, Lat: {}, Long: {}, Fix type: {:?}, Num sats: {}, Altitude: {}", 
-                    results.utc_time, results.latitude, results.longitude, results.fix_type, results.num_satellites, results.altitude_msl
-                );
-                board.radio.transmit_start(&[results.num_satellites]).unwrap();
+    // Printed with the info messages, so quieter builds leave it out
+    if log::enabled(log::Level::Info) { board.boot_log.print(); }
+    if let Some(crash) = crash_log::take_unreported() {
+        if log::enabled(log::Level::Error) { crash.print(); }
+        if config::BEACON_FORMAT == BeaconFormat::Binary {
+            // Nothing else is using the radio yet
+            if board.radio.transmit_frame_start(GROUND_STATION_ID, FrameKind::Crash, &crash.report().to_bytes()).is_ok() {
                 let _ = nb::block!(board.radio.transmit_is_complete());
-            },
-            Err(GgaParseError::NoFix) => (),
-            Err(GgaParseError::SerialError(_)) => (),
-            Err(e) => panic!("{:?}", e)
+            }
         }
     }
 
-    idle_loop(board);
+    // Sleep between tasks rather than spinning. See lpm.rs.
+    lpm::start_clock(&mut board.timer_b0);
+    if config::BEACON_FORMAT == BeaconFormat::Aprs {
+        aprs::configure_radio(&mut board.radio).unwrap();
+    }
+    if config::RELAY_MODE {
+        board.radio.recieve_start(None).unwrap();
+    }
+
+    // Reset if the housekeeping task stops running, or the lowest priority tasks are starved. See watchdog.rs.
+    let housekeeping_check_in = board.watchdog.register();
+    let led_check_in = board.watchdog.register();
+
+    let config = config_store::get();
+    let mut payload = Payload {
+        board,
+        housekeeping_check_in,
+        led_check_in,
+        buf: ArrayString::new(),
+        fix: None,
+        last_position: None,
+        hop_sequence: HopSequence::new(config.payload_id),
+        adr: AdrController::new(),
+        relay: Relay::new(config.payload_id),
+        rx_buf: [0u8; lora::RFM95_FIFO_SIZE],
+        beacon_slots: 0,
+        last_gps_activity: lpm::now(),
+        status_led: StatusLed::new(),
+        flight_log: FlightLog::open(),
+        #[cfg(feature = "console")]
+        console: Console::new(),
+    };
+
+    let mut scheduler: Scheduler<Payload, 8> = Scheduler::new();
+    scheduler.on("gps", 3, GPS_DATA, gps_task);
+    scheduler.on("beacon", 2, FIX, beacon_task);
+    if config::RELAY_MODE {
+        scheduler.on("relay", 2, RADIO, relay_task);
+    }
+    scheduler.every("housekeeping", 1, 1000, housekeeping_task);
+    scheduler.every("led", 0, 100, led_task);
+    #[cfg(feature = "console")]
+    scheduler.every("console", 0, 100, console_task);
+    scheduler.every("log", 0, config.flight_log_interval_s as u32 * 1000, log_task);
+    scheduler.every("watchdog", 0, 1000, |payload, _| { payload.board.watchdog.feed_if_all_checked_in(); });
+    if config::CURRENT_MEASUREMENT_MODE {
+        scheduler.every("current", 0, 10_000, |_, _| { lpm::print_current_estimate(); lpm::reset_stats(); });
+        scheduler.report_stats_every(10_000);
+    }
+    payload.board.watchdog.start(config::WATCHDOG_PERIOD);
+    scheduler.run(&mut payload, idle)
 }
 
-fn idle_loop(mut board: Board) -> ! {
-    loop {
-        // Snake the LEDs through the rainbow
-        const LED_DELAY_MS: u16 = 50; // ms
+// Events for the payload's tasks
+/// A byte has arrived from the GPS.
+const GPS_DATA: Event = Event(0);
+/// The radio may have received something.
+const RADIO: Event = Event(1);
+/// A new GPS fix is in `Payload::fix`.
+const FIX: Event = Event(2);
+
+/// Everything the payload's tasks share.
+struct Payload {
+    board: Board,
+    housekeeping_check_in: CheckIn,
+    led_check_in: CheckIn,
+    buf: ArrayString<82>,
+    /// The latest fix, until the beacon task takes it.
+    fix: Option<GgaMessage>,
+    /// Sent from deep sleep if the battery runs low.
+    last_position: Option<Beacon>,
+    hop_sequence: HopSequence,
+    adr: AdrController,
+    relay: Relay,
+    rx_buf: [u8; lora::RFM95_FIFO_SIZE],
+    beacon_slots: u8,
+    /// When we last heard from the GPS, from `lpm::now()`.
+    last_gps_activity: u32,
+    status_led: StatusLed,
+    flight_log: FlightLog,
+    #[cfg(feature = "console")]
+    console: Console,
+}
 
-        board.gpio.red_led.turn_on();
-        board.delay.delay_ms(LED_DELAY_MS);
+/// Report the GPS as silent if it hasn't sent anything for this long. It normally sends several sentences a second.
+const GPS_SILENT_MS: u32 = 5000;
 
-        board.gpio.green_led.turn_on();
-        board.delay.delay_ms(LED_DELAY_MS);
+/// Nothing to do until the GPS sends another byte or a task is due
+fn idle(_payload: &mut Payload, next_due: u32) -> Option<Event> {
+    match lpm::sleep_until(next_due, SleepMode::Lpm0, WakeSources { gps_uart: true, radio: config::RELAY_MODE }) {
+        WakeReason::Gps => Some(GPS_DATA),
+        WakeReason::Radio => Some(RADIO),
+        WakeReason::Deadline => None,
+    }
+}
 
-        board.gpio.blue_led.turn_on();
-        board.delay.delay_ms(LED_DELAY_MS);
+fn gps_task(payload: &mut Payload, signals: &mut Signals) {
+    payload.last_gps_activity = lpm::now();
+    match payload.board.gps.get_gga_message(&mut payload.buf) {
+        Ok(results) => {
+            debug!("Time: {}, Lat: {}, Long: {}, Fix type: {:?}, Num sats: {}, Altitude: {}", 
+                results.utc_time, results.latitude, results.longitude, results.fix_type, results.num_satellites, results.altitude_msl
+            );
+            let position = payload.board.beacon(&results, adr::FALLBACK_PROFILE);
+            if payload.flight_log.check_launch(position.altitude_decimetres) {
+                info!("Launch detected");
+                payload.flight_log.append(flight_log::Record::new(Some(&position), payload.board.battery.voltage_mv(), flight_log::flags::LAUNCH));
+            }
+            payload.last_position = Some(position);
+            payload.fix = Some(results);
+            payload.status_led.set_activity(Activity::FixAcquired);
+            signals.signal(FIX);
+        },
+        Err(nb::Error::WouldBlock) => (),
+        Err(nb::Error::Other(GgaParseError::NoFix)) => payload.status_led.set_activity(Activity::Searching),
+        Err(nb::Error::Other(GgaParseError::SerialError(_))) => (),
+        // Usually a sentence garbled in transit. The next one will probably be fine.
+        Err(nb::Error::Other(_)) => warn!("Couldn't parse GGA sentence"),
+    }
+}
 
-        board.gpio.red_led.turn_off();
-        board.delay.delay_ms(LED_DELAY_MS);
+fn beacon_task(payload: &mut Payload, _: &mut Signals) {
+    let Some(results) = payload.fix.take() else { return };
 
-        board.gpio.green_led.turn_off();
-        board.delay.delay_ms(LED_DELAY_MS);
+    // When sharing the channel with other payloads, wait our turn.
+    let config = config_store::get();
+    let our_turn = match config.tdma_schedule() {
+        Some(schedule) => schedule.may_transmit(config.payload_id, &results.utc_time),
+        None => true,
+    };
+    // On a low battery, skip most of our slots
+    let mut skip_slot = false;
+    if our_turn {
+        payload.beacon_slots = payload.beacon_slots.wrapping_add(1);
+        skip_slot = payload.board.power_stage() >= PowerStage::ReducedBeacons && !payload.beacon_slots.is_multiple_of(config::REDUCED_BEACON_DIVISOR);
+    }
+    if our_turn && skip_slot && config::FREQUENCY_HOPPING {
+        payload.hop_sequence.advance(); // Keep in step with the ground station
+    }
+    if our_turn && !skip_slot {
+        // The LED task can't run while we're blocked sending, so update the LED now
+        payload.status_led.set_activity(Activity::Transmitting);
+        payload.status_led.update(&mut payload.board.gpio);
+        transmit_beacon(payload, &results);
+        payload.status_led.set_activity(Activity::FixAcquired);
+        payload.status_led.update(&mut payload.board.gpio);
+    }
+    if config::RELAY_MODE {
+        payload.board.radio.recieve_start(None).unwrap();
+    }
+}
 
-        board.gpio.blue_led.turn_off();
-        board.delay.delay_ms(LED_DELAY_MS);
+/// Send our beacon, followed by as many of the frames we're relaying as fit in our slot. Blocks until they've all gone.
+fn transmit_beacon(payload: &mut Payload, results: &GgaMessage) {
+    let board = &mut payload.board;
+    // The fix arrives a little after the time it's for, so this is slightly generous
+    let slot_end = config_store::get().tdma_schedule()
+        .map(|schedule| lpm::now().wrapping_add(lpm::ms_to_ticks(schedule.millis_left_in_slot(&results.utc_time))));
+    if config::FREQUENCY_HOPPING {
+        board.radio.set_frequency(payload.hop_sequence.frequency_hz()).unwrap();
+        payload.hop_sequence.advance();
     }
+    if config::BEACON_FORMAT == BeaconFormat::Aprs {
+        let packet = aprs::encode(&aprs::position_from_gga(results)).unwrap();
+        board.radio.transmit_start(&packet).unwrap();
+        let _ = nb::block!(board.radio.transmit_is_complete());
+        return;
+    }
+    let beacon = board.beacon(results, payload.adr.next());
+    let seq = board.radio.transmit_frame_start(GROUND_STATION_ID, FrameKind::Beacon, &beacon.to_bytes()).unwrap();
+    let _ = nb::block!(board.radio.transmit_is_complete());
+
+    if config::ADAPTIVE_DATA_RATE {
+        match board.radio.wait_for_ack(GROUND_STATION_ID, seq) {
+            Some(ack) => payload.adr.ack_received(ack.snr),
+            None => payload.adr.ack_missed(),
+        }
+        board.radio.set_profile(&adr::PROFILES[payload.adr.current() as usize]).unwrap();
+    }
+
+    // Frames we're relaying for other payloads go out in our slot too. Any that would run into the next payload's slot
+    // wait for our next one.
+    if !config::RELAY_MODE { return }
+    while let Some(frame) = payload.relay.peek() {
+        if let Some(slot_end) = slot_end {
+            let airtime = board.radio.airtime(frame.len()).map_or(u32::MAX, |t| lpm::ms_to_ticks(t.as_millis() as u32 + 1));
+            let left = slot_end.wrapping_sub(lpm::now());
+            if left > u32::MAX / 2 || airtime > left { break }
+        }
+        let Some(frame) = payload.relay.next_to_send() else { break };
+        board.radio.transmit_start(&frame).unwrap();
+        let _ = nb::block!(board.radio.transmit_is_complete());
+    }
+}
+
+/// Listen for other payloads between beacons
+fn relay_task(payload: &mut Payload, _: &mut Signals) {
+    let radio = &mut payload.board.radio;
+    match radio.recieve_is_complete(&mut payload.rx_buf) {
+        Ok(msg) => {
+            if let Ok(frame) = Frame::parse(msg) {
+                if payload.relay.offer(&frame) {
+                    info!("Relaying beacon from payload {}", frame.header.src);
+                }
+            }
+            radio.recieve_start(None).unwrap();
+        },
+        Err(nb::Error::Other(_)) => radio.recieve_start(None).unwrap(), // Timeout or corrupted packet
+        Err(nb::Error::WouldBlock) => (),
+    }
+}
+
+/// Keep an eye on the battery and PSU, even without a GPS fix
+fn housekeeping_task(payload: &mut Payload, _: &mut Signals) {
+    let board = &mut payload.board;
+    board.watchdog.check_in(payload.housekeeping_check_in);
+    if let Some(rail) = board.rails.check_faults() {
+        warn!("Rail {:?} out of regulation, switched off", rail);
+    }
+    if let Some(level) = board.update_battery() {
+        info!("Battery {:?}: {}mV, {}%", level, board.battery.voltage_mv(), board.battery.state_of_charge_percent());
+    }
+    if let Some(stage) = board.apply_power_policy() {
+        warn!("Power stage: {:?}", stage);
+        if stage == PowerStage::DeepSleep {
+            board.low_battery_sleep(payload.last_position.take(), &mut payload.hop_sequence);
+        }
+    }
+
+    let gps_silent = lpm::now().wrapping_sub(payload.last_gps_activity) > lpm::ms_to_ticks(GPS_SILENT_MS);
+    let fault = if board.rails.has_fault() { Some(BlinkCode::RailFault) }
+        else if gps_silent { Some(BlinkCode::GpsSilent) }
+        else if board.battery.level() <= BatteryLevel::Low { Some(BlinkCode::LowBattery) }
+        else { None };
+    payload.status_led.set_fault(fault);
+}
+
+/// Record where we are in the flight log, even without a fix
+fn log_task(payload: &mut Payload, _: &mut Signals) {
+    let board = &payload.board;
+    let mut flags = 0;
+    if board.battery.level() <= BatteryLevel::Low { flags |= flight_log::flags::LOW_BATTERY }
+    if board.rails.has_fault() { flags |= flight_log::flags::RAIL_FAULT }
+    payload.flight_log.append(flight_log::Record::new(payload.last_position.as_ref(), board.battery.voltage_mv(), flags));
+}
+
+/// Run commands typed on the debug UART. See console.rs.
+#[cfg(feature = "console")]
+fn console_task(payload: &mut Payload, _: &mut Signals) {
+    let mut context = console::Context {
+        board: &mut payload.board,
+        flight_log: &mut payload.flight_log,
+        last_position: payload.last_position.as_ref(),
+    };
+    payload.console.poll(&mut context);
+}
+
+fn led_task(payload: &mut Payload, _: &mut Signals) {
+    payload.status_led.update(&mut payload.board.gpio);
+    payload.board.watchdog.check_in(payload.led_check_in);
 }
@@ -1,29 +1,33 @@
 // Our panic handler. Currently we print strings here for maximum debuggability. String printing is quite expensive in terms of executable size,
 // so if you're running out of space consider commenting out some of these print statements (or uncommenting `strip = true` in cargo.toml!).
-use crate::{print, println, stdlib_println};
+//
+// Nothing is listening to the debug UART in flight, so the panic is also saved to FRAM (see `crash_log.rs`), its line
+// number is blinked on the LED (see `blink_code.rs`) and we reset to get the payload going again.
+use crate::{blink_code::{BlinkCode, PanicLeds, SpinDelay}, print, println, serial::OverflowPolicy};
 use core::panic::PanicInfo;
 #[panic_handler]
 fn panic_handler(panic_info: &PanicInfo) -> ! {
     msp430::interrupt::disable();
+    crate::watchdog::hold_now(); // Don't reset until we've finished blinking
+    crate::crash_log::record_panic(panic_info);
 
     let serial_configured = msp430::critical_section::with(|cs| { crate::serial::SERIAL.borrow_ref(cs).is_some() });
     if serial_configured {
+        crate::serial::set_overflow_policy(OverflowPolicy::Block); // Interrupts are off, so the queue won't empty itself
         print!("Panic: ");
         if let Some(location) = panic_info.location() {
             // Printing code locations adds a lot of executable size
             println!("File: {}, line: {}, col: {},", location.file(), location.line(), location.column())
         }
+        // Messages with arguments aren't printed. ufmt can't format them, and core::fmt takes too much space.
         if let Some(message) = panic_info.message().as_str() {
-            print!("{}", message);
-        }
-        else {
-            // Unfortunately we can't print PanicMessage using ufmt because it doesn't implement uDisplay or uDebug.
-            // The below code pulls in Rust's standard printing library. This takes more executable space, remove it if you need to.
-            stdlib_println!("{}", panic_info.message());
-            //println!("Can't print message");
+            println!("{}", message);
         }
+        crate::serial::flush();
     }
-    loop { msp430::asm::barrier(); }
+    let line = panic_info.location().map_or(0, |location| location.line());
+    BlinkCode::Panic { line }.show(&mut PanicLeds::steal(), &mut SpinDelay);
+    crate::watchdog::reset_now()
 }
 
 // The compiler will emit calls to the abort() compiler intrinsic if debug assertions are
@@ -25,6 +25,8 @@ pub type LoraCsPin          = Pin<P4, Pin4, Output>;
 
 pub type DebugEusci         = E_USCI_A0;
 pub type DebugTxPin:        = Pin<P1, Pin7, Alternate1<Input<Floating>>>;
+pub type DebugRxPin:        = Pin<P1, Pin6, Alternate1<Input<Floating>>>;
+pub type DebugRx            = Rx<E_USCI_A0>;
 
 pub type I2cSdaPin:         = Pin<P1, Pin2, Alternate1<Input<Floating>>>;
 pub type I2cSclPin:         = Pin<P1, Pin3, Alternate1<Input<Floating>>>;
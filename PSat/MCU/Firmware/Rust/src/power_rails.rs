@@ -0,0 +1,164 @@
+// Control and supervision of the PSU's switchable rails.
+//
+// The 1.8V and 5V rails can be switched on and off. The 1.8V and 3.3V rails have power-good outputs, which go low when
+// the rail is out of regulation. The 3.3V rail powers the MCU so it is always on, but we still watch its power-good.
+// The 5V rail has no power-good, so we can switch it but can't tell whether it's healthy.
+//
+// Rails that fall out of regulation are switched off and counted as faults. The counts are sent in each beacon.
+
+use embedded_hal::{blocking::delay::DelayMs, digital::v2::{InputPin, OutputPin}};
+use ufmt::derive::uDebug;
+use crate::pin_mappings::{Enable1v8Pin, Enable5vPin, PowerGood1v8Pin, PowerGood3v3Pin};
+
+/// How long a rail has to come into regulation after being enabled.
+const SOFT_START_TIMEOUT_MS: u16 = 20;
+/// Time allowed for each rail to settle before enabling the next one in `power_up()`. Limits inrush current.
+const SEQUENCE_DELAY_MS: u16 = 5;
+
+#[derive(Debug, uDebug, Clone, Copy, PartialEq, Eq)]
+pub enum Rail {
+    V1_8 = 0,
+    V3_3 = 1,
+    V5 = 2,
+}
+
+#[derive(Debug, uDebug, Clone, Copy, PartialEq, Eq)]
+pub enum RailStatus {
+    Off,
+    /// Enabled and in regulation.
+    Good,
+    /// Enabled, but the rail has no power-good output so we can't tell if it's healthy.
+    Unmonitored,
+    /// Switched off after falling out of regulation. Call `enable()` to try again.
+    Fault,
+}
+
+#[derive(Debug, uDebug, Clone, Copy, PartialEq, Eq)]
+pub enum RailError {
+    /// The rail didn't come into regulation within `SOFT_START_TIMEOUT_MS`, so it was switched off again.
+    SoftStartTimeout,
+    /// The 3.3V rail is always on.
+    NotSwitchable,
+}
+
+pub struct PowerRails {
+    enable_1v8: Enable1v8Pin,
+    enable_5v: Enable5vPin,
+    power_good_1v8: PowerGood1v8Pin,
+    power_good_3v3: PowerGood3v3Pin,
+    /// Indexed by `Rail`.
+    status: [RailStatus; 3],
+    fault_counts: [u8; 3],
+}
+impl PowerRails {
+    /// Takes the rail pins, switching the 1.8V and 5V rails off.
+    pub fn new(mut enable_1v8: Enable1v8Pin, mut enable_5v: Enable5vPin, power_good_1v8: PowerGood1v8Pin, power_good_3v3: PowerGood3v3Pin) -> Self {
+        enable_1v8.set_low().ok();
+        enable_5v.set_low().ok();
+        Self {
+            enable_1v8, enable_5v, power_good_1v8, power_good_3v3,
+            status: [RailStatus::Off, RailStatus::Good, RailStatus::Off],
+            fault_counts: [0; 3],
+        }
+    }
+
+    /// Switch a rail on and wait for it to come into regulation.
+    pub fn enable(&mut self, rail: Rail, delay: &mut impl DelayMs<u16>) -> Result<(), RailError> {
+        match rail {
+            Rail::V1_8 => self.enable_1v8.set_high().ok(),
+            Rail::V5 => self.enable_5v.set_high().ok(),
+            Rail::V3_3 => return Err(RailError::NotSwitchable),
+        };
+
+        if !self.has_power_good(rail) {
+            self.status[rail as usize] = RailStatus::Unmonitored;
+            return Ok(());
+        }
+        for _ in 0..SOFT_START_TIMEOUT_MS {
+            if self.power_good(rail) {
+                self.status[rail as usize] = RailStatus::Good;
+                return Ok(());
+            }
+            delay.delay_ms(1);
+        }
+        self.record_fault(rail);
+        Err(RailError::SoftStartTimeout)
+    }
+
+    pub fn disable(&mut self, rail: Rail) -> Result<(), RailError> {
+        match rail {
+            Rail::V1_8 => self.enable_1v8.set_low().ok(),
+            Rail::V5 => self.enable_5v.set_low().ok(),
+            Rail::V3_3 => return Err(RailError::NotSwitchable),
+        };
+        self.status[rail as usize] = RailStatus::Off;
+        Ok(())
+    }
+
+    #[cfg(feature = "console")]
+    pub fn status(&self, rail: Rail) -> RailStatus {
+        self.status[rail as usize]
+    }
+
+    /// Bring up the switchable rails one at a time, 1.8V first. Stops at the first rail that fails.
+    pub fn power_up(&mut self, delay: &mut impl DelayMs<u16>) -> Result<(), (Rail, RailError)> {
+        for rail in [Rail::V1_8, Rail::V5] {
+            self.enable(rail, delay).map_err(|e| (rail, e))?;
+            delay.delay_ms(SEQUENCE_DELAY_MS);
+        }
+        Ok(())
+    }
+
+    /// Check that every enabled rail is still in regulation. Call this periodically.
+    ///
+    /// Rails that have dropped out are switched off. Returns the first rail that faulted, if any.
+    pub fn check_faults(&mut self) -> Option<Rail> {
+        let mut faulted = None;
+        for rail in [Rail::V1_8, Rail::V3_3] {
+            let status = self.status[rail as usize];
+            if status == RailStatus::Good && !self.power_good(rail) {
+                self.record_fault(rail);
+                faulted = faulted.or(Some(rail));
+            }
+            else if rail == Rail::V3_3 && status == RailStatus::Fault && self.power_good(rail) {
+                // The 3.3V rail can't be switched off, so it can recover on its own
+                self.status[rail as usize] = RailStatus::Good;
+            }
+        }
+        faulted
+    }
+
+    /// Whether any rail is currently switched off because of a fault.
+    pub fn has_fault(&self) -> bool {
+        self.status.contains(&RailStatus::Fault)
+    }
+
+    /// Number of times a rail has fallen out of regulation since boot. Saturates at 255.
+    pub fn fault_count(&self, rail: Rail) -> u8 {
+        self.fault_counts[rail as usize]
+    }
+
+    /// Fault counts packed into a byte for telemetry: 1.8V in the low nibble, 3.3V in the high nibble. Each saturates at 15.
+    pub fn packed_fault_counts(&self) -> u8 {
+        let nibble = |rail: Rail| self.fault_count(rail).min(15);
+        nibble(Rail::V1_8) | nibble(Rail::V3_3) << 4
+    }
+
+    fn has_power_good(&self, rail: Rail) -> bool {
+        rail != Rail::V5
+    }
+
+    fn power_good(&self, rail: Rail) -> bool {
+        match rail {
+            Rail::V1_8 => self.power_good_1v8.is_high().unwrap_or(false),
+            Rail::V3_3 => self.power_good_3v3.is_high().unwrap_or(false),
+            Rail::V5 => true,
+        }
+    }
+
+    fn record_fault(&mut self, rail: Rail) {
+        self.fault_counts[rail as usize] = self.fault_counts[rail as usize].saturating_add(1);
+        self.disable(rail).ok();
+        self.status[rail as usize] = RailStatus::Fault;
+    }
+}
@@ -0,0 +1,275 @@
+// Over-the-air frame format shared by the payloads and the ground station.
+//
+// Every frame starts with a small fixed header so that a receiver can tell payloads apart and ignore
+// frames that weren't meant for it:
+//
+// | byte | 0           | 1      | 2    | 3        | 4   | 5..     |
+// |------|-------------|--------|------|----------|-----|---------|
+// |      | destination | source | kind | sequence | TTL | payload |
+//
+// The source is always the radio that created the frame, even if it was relayed by other payloads on the way (see `relay.rs`).
+// TTL is the number of further times the frame may be relayed.
+//
+// Multi-byte values in payloads are little-endian.
+
+#![allow(dead_code)]
+use arrayvec::ArrayVec;
+use crate::gps::GgaMessage;
+
+/// Address of the ground station.
+pub const GROUND_STATION_ID: u8 = 0x00;
+/// Frames sent to this address are accepted by every receiver.
+pub const BROADCAST_ID: u8 = 0xFF;
+
+pub const HEADER_LEN: usize = 5;
+/// TTL given to new frames, i.e. the maximum number of relays between a payload and the ground station.
+pub const DEFAULT_TTL: u8 = 2;
+/// Largest frame we ever build. Kept well below `RFM95_FIFO_SIZE` to save RAM (and long frames mean long airtime anyway).
+pub const MAX_FRAME_LEN: usize = 48;
+pub const MAX_PAYLOAD_LEN: usize = MAX_FRAME_LEN - HEADER_LEN;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum FrameKind {
+    /// Periodic position report from a payload. See `Beacon`.
+    Beacon = 0x01,
+    /// Sent by `lora::tests::range_test_tx()`. See `RangeTest`.
+    RangeTest = 0x02,
+    /// Sent by the ground station in reply to a beacon. See `Ack`.
+    Ack = 0x03,
+    /// Sent by a payload after it resets because of a panic. See `CrashReport`.
+    Crash = 0x04,
+}
+impl TryFrom<u8> for FrameKind {
+    type Error = FrameError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x01 => Ok(FrameKind::Beacon),
+            0x02 => Ok(FrameKind::RangeTest),
+            0x03 => Ok(FrameKind::Ack),
+            0x04 => Ok(FrameKind::Crash),
+            _ => Err(FrameError::UnknownKind(value)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct FrameHeader {
+    pub dest: u8,
+    pub src: u8,
+    pub kind: FrameKind,
+    /// Incremented by the sender for every frame it sends. Wraps around.
+    pub seq: u8,
+    pub ttl: u8,
+}
+impl FrameHeader {
+    /// Whether a radio with address `address` should accept this frame.
+    pub fn is_for(&self, address: u8) -> bool {
+        self.dest == address || self.dest == BROADCAST_ID
+    }
+
+    /// The number of times this frame has been relayed.
+    pub fn hops(&self) -> u8 {
+        DEFAULT_TTL.saturating_sub(self.ttl)
+    }
+}
+
+/// A received frame. The payload borrows from the receive buffer.
+pub struct Frame<'a> {
+    pub header: FrameHeader,
+    pub payload: &'a [u8],
+}
+impl<'a> Frame<'a> {
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, FrameError> {
+        if bytes.len() < HEADER_LEN { return Err(FrameError::TooShort) }
+
+        let header = FrameHeader {
+            dest: bytes[0],
+            src:  bytes[1],
+            kind: FrameKind::try_from(bytes[2])?,
+            seq:  bytes[3],
+            ttl:  bytes[4],
+        };
+        Ok(Frame { header, payload: &bytes[HEADER_LEN..] })
+    }
+
+    /// Serialise a header and payload, ready to be handed to the radio.
+    pub fn encode(header: &FrameHeader, payload: &[u8]) -> Result<ArrayVec<u8, MAX_FRAME_LEN>, FrameError> {
+        if payload.len() > MAX_PAYLOAD_LEN { return Err(FrameError::TooLong) }
+
+        let mut frame = ArrayVec::new();
+        frame.extend([header.dest, header.src, header.kind as u8, header.seq, header.ttl]);
+        frame.try_extend_from_slice(payload).map_err(|_| FrameError::TooLong)?;
+        Ok(frame)
+    }
+}
+
+#[derive(Debug)]
+pub enum FrameError {
+    TooShort,
+    TooLong,
+    UnknownKind(u8),
+}
+
+/// Position report sent periodically by each payload.
+pub struct Beacon {
+    /// Milliseconds since midnight UTC, as reported by the GPS.
+    pub utc_millis: u32,
+    pub latitude_microdegrees: i32,
+    pub longitude_microdegrees: i32,
+    pub altitude_decimetres: i32,
+    pub num_satellites: u8,
+    /// The link profile the payload will switch to once this beacon is ACKed. See `adr.rs`.
+    pub next_profile: u8,
+    /// PSU rail fault counts, see `PowerRails::packed_fault_counts()`.
+    pub rail_faults: u8,
+    /// Number of times the payload has booted. See `boot_log.rs`.
+    pub boot_count: u16,
+}
+impl Beacon {
+    pub const LEN: usize = 21;
+
+    pub fn from_gga(gga: &GgaMessage, next_profile: u8) -> Self {
+        Beacon {
+            utc_millis: gga.utc_time.millis_of_day(),
+            latitude_microdegrees: gga.latitude.as_microdegrees(),
+            longitude_microdegrees: gga.longitude.as_microdegrees(),
+            altitude_decimetres: gga.altitude_msl.decimetres(),
+            num_satellites: gga.num_satellites,
+            next_profile,
+            rail_faults: 0,
+            boot_count: 0,
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; Self::LEN] {
+        let mut bytes = [0u8; Self::LEN];
+        bytes[0..4].copy_from_slice(&self.utc_millis.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.latitude_microdegrees.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.longitude_microdegrees.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.altitude_decimetres.to_le_bytes());
+        bytes[16] = self.num_satellites;
+        bytes[17] = self.next_profile;
+        bytes[18] = self.rail_faults;
+        bytes[19..21].copy_from_slice(&self.boot_count.to_le_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, FrameError> {
+        if bytes.len() < Self::LEN { return Err(FrameError::TooShort) }
+
+        let word = |i: usize| [bytes[i], bytes[i+1], bytes[i+2], bytes[i+3]];
+        Ok(Beacon {
+            utc_millis:             u32::from_le_bytes(word(0)),
+            latitude_microdegrees:  i32::from_le_bytes(word(4)),
+            longitude_microdegrees: i32::from_le_bytes(word(8)),
+            altitude_decimetres:    i32::from_le_bytes(word(12)),
+            num_satellites: bytes[16],
+            next_profile: bytes[17],
+            rail_faults: bytes[18],
+            boot_count: u16::from_le_bytes([bytes[19], bytes[20]]),
+        })
+    }
+}
+
+/// Range test packet. The sequence number lets the receiver count lost packets.
+pub struct RangeTest {
+    pub seq: u16,
+    /// Seconds since the transmitter started.
+    pub tx_time_s: u32,
+}
+impl RangeTest {
+    pub const LEN: usize = 6;
+
+    pub fn to_bytes(&self) -> [u8; Self::LEN] {
+        let [s0, s1] = self.seq.to_le_bytes();
+        let [t0, t1, t2, t3] = self.tx_time_s.to_le_bytes();
+        [s0, s1, t0, t1, t2, t3]
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, FrameError> {
+        if bytes.len() < Self::LEN { return Err(FrameError::TooShort) }
+
+        Ok(RangeTest {
+            seq: u16::from_le_bytes([bytes[0], bytes[1]]),
+            tx_time_s: u32::from_le_bytes([bytes[2], bytes[3], bytes[4], bytes[5]]),
+        })
+    }
+}
+
+/// Reply from the ground station to a beacon, reporting how well it was received.
+pub struct Ack {
+    /// Sequence number from the header of the frame being acknowledged.
+    pub acked_seq: u8,
+    /// SNR of the acknowledged frame in dB.
+    pub snr: i8,
+    /// RSSI of the acknowledged frame in dBm.
+    pub rssi: i16,
+}
+impl Ack {
+    pub const LEN: usize = 4;
+
+    pub fn to_bytes(&self) -> [u8; Self::LEN] {
+        let [r0, r1] = self.rssi.to_le_bytes();
+        [self.acked_seq, self.snr as u8, r0, r1]
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, FrameError> {
+        if bytes.len() < Self::LEN { return Err(FrameError::TooShort) }
+
+        Ok(Ack {
+            acked_seq: bytes[0],
+            snr: bytes[1] as i8,
+            rssi: i16::from_le_bytes([bytes[2], bytes[3]]),
+        })
+    }
+}
+
+/// Where a payload panicked, sent once after the reset that follows. See `crash_log.rs`.
+///
+/// | byte | 0..2       | 2..4 | 4..6   | 6        | 7..             | ..      |
+/// |------|------------|------|--------|----------|-----------------|---------|
+/// |      | boot count | line | column | file len | end of the path | message |
+pub struct CrashReport<'a> {
+    /// The boot during which the panic happened.
+    pub boot_count: u16,
+    pub line: u16,
+    pub column: u16,
+    /// The end of the source file's path.
+    pub file: &'a [u8],
+    /// The start of the panic message, cut short to fit the frame.
+    pub message: &'a [u8],
+}
+impl<'a> CrashReport<'a> {
+    /// Longest file path tail sent. Leaves room for some of the message.
+    pub const MAX_FILE_LEN: usize = 16;
+
+    pub fn to_bytes(&self) -> ArrayVec<u8, MAX_PAYLOAD_LEN> {
+        let file = &self.file[self.file.len().saturating_sub(Self::MAX_FILE_LEN)..];
+        let [b0, b1] = self.boot_count.to_le_bytes();
+        let [l0, l1] = self.line.to_le_bytes();
+        let [c0, c1] = self.column.to_le_bytes();
+        // None of these can fail, as the message is cut to the space left. Unlike `extend()`, they don't link in a panic.
+        let mut bytes = ArrayVec::new();
+        let _ = bytes.try_extend_from_slice(&[b0, b1, l0, l1, c0, c1, file.len() as u8]);
+        let _ = bytes.try_extend_from_slice(file);
+        let message_len = self.message.len().min(bytes.remaining_capacity());
+        let _ = bytes.try_extend_from_slice(&self.message[..message_len]);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &'a [u8]) -> Result<Self, FrameError> {
+        if bytes.len() < 7 { return Err(FrameError::TooShort) }
+
+        let file_end = 7 + bytes[6] as usize;
+        if bytes.len() < file_end { return Err(FrameError::TooShort) }
+        Ok(CrashReport {
+            boot_count: u16::from_le_bytes([bytes[0], bytes[1]]),
+            line: u16::from_le_bytes([bytes[2], bytes[3]]),
+            column: u16::from_le_bytes([bytes[4], bytes[5]]),
+            file: &bytes[7..file_end],
+            message: &bytes[file_end..],
+        })
+    }
+}
@@ -0,0 +1,84 @@
+// Mesh relay. A payload that can't reach the ground station directly (e.g. it landed in a gully) can still be found if
+// another payload can hear it. With `config::RELAY_MODE` enabled, a payload listens for other payloads' beacons between
+// its own and re-broadcasts them, decrementing the TTL in the header each time.
+//
+// Every receiver remembers which frames it has recently seen, so a frame is only ever relayed (or printed by the ground
+// station) once, no matter how many paths it arrives by.
+
+#![allow(dead_code)]
+use arrayvec::ArrayVec;
+use crate::protocol::{Frame, FrameHeader, FrameKind, GROUND_STATION_ID, MAX_FRAME_LEN};
+
+/// How many recent frames we remember. Needs to cover every frame that could still be bouncing around the mesh.
+const DUPLICATE_CACHE_LEN: usize = 16;
+/// Frames waiting to be relayed. If this fills up, new frames are dropped until we get a chance to transmit.
+const RELAY_QUEUE_LEN: usize = 2;
+
+/// Remembers the source and sequence number of recently seen frames.
+pub struct DuplicateCache {
+    seen: [(u8, u8); DUPLICATE_CACHE_LEN],
+    len: u8,
+    next: u8,
+}
+impl DuplicateCache {
+    pub fn new() -> Self {
+        Self { seen: [(0, 0); DUPLICATE_CACHE_LEN], len: 0, next: 0 }
+    }
+
+    /// Returns `true` if this frame has been seen before. Otherwise remembers it and returns `false`.
+    pub fn check_and_insert(&mut self, header: &FrameHeader) -> bool {
+        let key = (header.src, header.seq);
+        if self.seen[..self.len as usize].contains(&key) {
+            return true;
+        }
+
+        self.seen[self.next as usize] = key;
+        self.next = (self.next + 1) % DUPLICATE_CACHE_LEN as u8;
+        self.len = (self.len + 1).min(DUPLICATE_CACHE_LEN as u8);
+        false
+    }
+}
+
+pub struct Relay {
+    own_address: u8,
+    seen: DuplicateCache,
+    queue: ArrayVec<ArrayVec<u8, MAX_FRAME_LEN>, RELAY_QUEUE_LEN>,
+}
+impl Relay {
+    pub fn new(own_address: u8) -> Self {
+        Self { own_address, seen: DuplicateCache::new(), queue: ArrayVec::new() }
+    }
+
+    /// Offer a received frame to the relay. Beacons from other payloads that still have some TTL left are queued for re-broadcast.
+    ///
+    /// Returns `true` if the frame was queued.
+    pub fn offer(&mut self, frame: &Frame) -> bool {
+        let header = frame.header;
+        if header.kind != FrameKind::Beacon
+            || header.src == self.own_address
+            || header.src == GROUND_STATION_ID
+            || header.ttl == 0
+        {
+            return false;
+        }
+        if self.seen.check_and_insert(&header) || self.queue.is_full() {
+            return false;
+        }
+
+        let relayed = FrameHeader { ttl: header.ttl - 1, ..header };
+        match Frame::encode(&relayed, frame.payload) {
+            Ok(bytes) => { self.queue.push(bytes); true },
+            Err(_) => false,
+        }
+    }
+
+    /// The next frame waiting to be relayed, without taking it off the queue.
+    pub fn peek(&self) -> Option<&[u8]> {
+        self.queue.first().map(|frame| frame.as_slice())
+    }
+
+    /// The next frame waiting to be relayed, if any. Pass it straight to `Radio::transmit_start()`.
+    pub fn next_to_send(&mut self) -> Option<ArrayVec<u8, MAX_FRAME_LEN>> {
+        self.queue.pop_at(0)
+    }
+}
@@ -0,0 +1,61 @@
+// Why the MCU last reset, from the SYS module's reset interrupt vector (SYSRSTIV).
+//
+// SYSRSTIV queues every cause since the last time it was read, and reading it pops the highest priority one. `read()`
+// drains the queue so the next reset starts from a clean slate.
+
+#![allow(dead_code)]
+use msp430fr2355::SYS;
+use ufmt::derive::uDebug;
+
+#[derive(Debug, uDebug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetCause {
+    /// Brownout reset, which is also how every power-up starts. A supply dip deep enough to trip it looks the same.
+    PowerOn,
+    /// The supply dropped below the high side supervisor (SVSH) threshold while running.
+    Brownout,
+    /// The RST pin was pulled low, e.g. by a programmer.
+    ResetPin,
+    /// A software BOR or POR, requested through the PMM.
+    Software,
+    /// The watchdog wasn't fed in time.
+    Watchdog,
+    /// Something wrote to the watchdog without the password. Also how we deliberately reset from the panic handler.
+    WatchdogPassword,
+    /// Any other SYSRSTIV value, e.g. an FRAM error or another peripheral password violation.
+    Other(u8),
+}
+impl ResetCause {
+    /// Read and clear the reset cause. Call this once, early in boot.
+    pub fn read() -> Self {
+        let sys = unsafe { &*SYS::ptr() };
+        let cause = Self::from_vector(sys.sysrstiv.read().bits());
+        while sys.sysrstiv.read().bits() != 0 {}
+        cause
+    }
+
+    /// Convert from a SYSRSTIV value.
+    pub fn from_vector(vector: u16) -> Self {
+        match vector {
+            0x00 | 0x02 => ResetCause::PowerOn,
+            0x04 => ResetCause::ResetPin,
+            0x06 | 0x14 => ResetCause::Software,
+            0x0E => ResetCause::Brownout,
+            0x16 => ResetCause::Watchdog,
+            0x18 => ResetCause::WatchdogPassword,
+            _ => ResetCause::Other(vector as u8),
+        }
+    }
+
+    /// A SYSRSTIV value for this cause, for storing compactly. Never 0.
+    pub fn vector(self) -> u8 {
+        match self {
+            ResetCause::PowerOn => 0x02,
+            ResetCause::ResetPin => 0x04,
+            ResetCause::Software => 0x06,
+            ResetCause::Brownout => 0x0E,
+            ResetCause::Watchdog => 0x16,
+            ResetCause::WatchdogPassword => 0x18,
+            ResetCause::Other(vector) => vector,
+        }
+    }
+}
@@ -0,0 +1,163 @@
+// A small cooperative scheduler. Tasks are plain functions that run to completion, either periodically or when an
+// event is signalled (by another task, or by whatever woke us from sleep). When several tasks are ready, the one with
+// the highest priority runs first. When none are ready, the scheduler sleeps until the next periodic task is due.
+//
+// Everything is statically sized, so the only RAM used is `N` task slots in the `Scheduler` itself.
+//
+// Tasks can't be preempted, so a slow task delays everything else. `print_stats()` shows the longest each task has run for.
+
+use arrayvec::ArrayVec;
+use crate::{lpm::{self, Periodic}, println};
+
+/// An event that tasks can wait on. Each application defines its own, numbered 0 to 7.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Event(pub u8);
+
+/// Events signalled by a task while it ran. They're delivered once it returns.
+#[derive(Clone, Copy, Default)]
+pub struct Signals(u8);
+impl Signals {
+    pub fn signal(&mut self, event: Event) {
+        self.0 |= 1 << event.0;
+    }
+
+    fn contains(&self, event: Event) -> bool {
+        self.0 & (1 << event.0) != 0
+    }
+}
+
+pub type TaskFn<C> = fn(&mut C, &mut Signals);
+
+enum Trigger {
+    Every(Periodic),
+    On(Event),
+}
+
+struct Task<C> {
+    name: &'static str,
+    /// Higher runs first.
+    priority: u8,
+    trigger: Trigger,
+    run: TaskFn<C>,
+    /// Whether the task's event has been signalled since it last ran.
+    signalled: bool,
+    runs: u32,
+    max_runtime_ticks: u32,
+}
+
+/// Schedules up to `N` tasks, which all share the context `C`.
+pub struct Scheduler<C, const N: usize> {
+    tasks: ArrayVec<Task<C>, N>,
+    stats_report: Option<Periodic>,
+}
+impl<C, const N: usize> Scheduler<C, N> {
+    pub fn new() -> Self {
+        Self { tasks: ArrayVec::new(), stats_report: None }
+    }
+
+    /// Run `task` every `period_ms`. Panics if there's no room left.
+    pub fn every(&mut self, name: &'static str, priority: u8, period_ms: u32, task: TaskFn<C>) {
+        self.add(name, priority, Trigger::Every(Periodic::every_ms(period_ms)), task);
+    }
+
+    /// Run `task` whenever `event` is signalled. Signalling an event more than once before the task runs only runs it once.
+    pub fn on(&mut self, name: &'static str, priority: u8, event: Event, task: TaskFn<C>) {
+        self.add(name, priority, Trigger::On(event), task);
+    }
+
+    /// Print `print_stats()` every `period_ms`.
+    pub fn report_stats_every(&mut self, period_ms: u32) {
+        self.stats_report = Some(Periodic::every_ms(period_ms));
+    }
+
+    pub fn signal(&mut self, event: Event) {
+        for task in self.tasks.iter_mut() {
+            if matches!(task.trigger, Trigger::On(e) if e == event) {
+                task.signalled = true;
+            }
+        }
+    }
+
+    /// Run tasks forever. When nothing is ready, `idle` is called with the time (from `lpm::now()`) that the next periodic
+    /// task is due. It should sleep until then, and may return early with an event, e.g. because an interrupt woke us.
+    pub fn run(mut self, context: &mut C, idle: fn(&mut C, u32) -> Option<Event>) -> ! {
+        loop {
+            if let Some(report) = &mut self.stats_report {
+                if report.poll() { self.print_stats() }
+            }
+
+            match self.next_ready() {
+                Some(index) => self.run_task(index, context),
+                None => {
+                    if let Some(event) = idle(context, self.next_due()) {
+                        self.signal(event);
+                    }
+                },
+            }
+        }
+    }
+
+    /// Print how often each task has run and the longest it has taken.
+    pub fn print_stats(&self) {
+        println!("Task | Runs | Max (ms)");
+        for task in self.tasks.iter() {
+            println!("{} | {} | {}", task.name, task.runs, lpm::ticks_to_ms(task.max_runtime_ticks));
+        }
+    }
+
+    fn add(&mut self, name: &'static str, priority: u8, trigger: Trigger, run: TaskFn<C>) {
+        let task = Task { name, priority, trigger, run, signalled: false, runs: 0, max_runtime_ticks: 0 };
+        if self.tasks.try_push(task).is_err() {
+            panic!("Too many tasks");
+        }
+    }
+
+    /// The highest priority task that's ready to run. Ties go to whichever was added first.
+    fn next_ready(&self) -> Option<usize> {
+        let mut best: Option<usize> = None;
+        for (index, task) in self.tasks.iter().enumerate() {
+            let ready = match &task.trigger {
+                Trigger::Every(periodic) => lpm::is_due(periodic.due_at()),
+                Trigger::On(_) => task.signalled,
+            };
+            if ready && best.is_none_or(|b| task.priority > self.tasks[b].priority) {
+                best = Some(index);
+            }
+        }
+        best
+    }
+
+    fn run_task(&mut self, index: usize, context: &mut C) {
+        let task = &mut self.tasks[index];
+        match &mut task.trigger {
+            Trigger::Every(periodic) => { periodic.poll(); },
+            Trigger::On(_) => task.signalled = false,
+        }
+
+        let mut signals = Signals::default();
+        let start = lpm::now();
+        (task.run)(context, &mut signals);
+        let runtime = lpm::now().wrapping_sub(start);
+
+        task.runs = task.runs.saturating_add(1);
+        task.max_runtime_ticks = task.max_runtime_ticks.max(runtime);
+
+        for event in (0..8).map(Event) {
+            if signals.contains(event) { self.signal(event) }
+        }
+    }
+
+    /// When the next periodic task is due. If there aren't any, a second from now.
+    fn next_due(&self) -> u32 {
+        let mut next = lpm::now().wrapping_add(lpm::TICKS_PER_SECOND);
+        for task in self.tasks.iter() {
+            if let Trigger::Every(periodic) = &task.trigger {
+                next = lpm::earliest(next, periodic.due_at());
+            }
+        }
+        if let Some(report) = &self.stats_report {
+            next = lpm::earliest(next, report.due_at());
+        }
+        next
+    }
+}
@@ -1,9 +1,11 @@
-use msp430fr2x5x_hal::{clock::Smclk, serial::{BitCount, BitOrder, Loopback, Parity, StopBits, Tx}};
+use embedded_hal::serial::Read;
+use msp430fr2355::{interrupt, E_USCI_A0};
+use msp430fr2x5x_hal::{clock::Smclk, serial::{BitCount, BitOrder, Loopback, Parity, RecvError, StopBits, Tx}};
 
-/// Configure the debug UART for use with println!().
-pub fn configure_debug_serial(pin: DebugTxPin, smclk: &Smclk, debug_eusci: DebugEusci) {
+/// Configure the debug UART for use with println!(), and to receive bytes for `read_byte()`.
+pub fn configure_debug_serial(tx_pin: DebugTxPin, rx_pin: DebugRxPin, smclk: &Smclk, debug_eusci: DebugEusci) {
     pub const DEBUG_SERIAL_BAUD: u32 = 115200;
-    let debug_uart = msp430fr2x5x_hal::serial::SerialConfig::new(debug_eusci, 
+    let (debug_uart, mut debug_rx) = msp430fr2x5x_hal::serial::SerialConfig::new(debug_eusci, 
         BitOrder::LsbFirst, 
         BitCount::EightBits, 
         StopBits::OneStopBit, 
@@ -11,13 +13,162 @@ pub fn configure_debug_serial(pin: DebugTxPin, smclk: &Smclk, debug_eusci: Debug
         Loopback::NoLoop, 
         DEBUG_SERIAL_BAUD)
         .use_smclk(smclk)
-        .tx_only(pin);
+        .split(tx_pin, rx_pin);
 
-    let debug_uart = crate::serial::PrintableSerial(debug_uart);
+    let mut debug_uart = crate::serial::PrintableSerial::new(debug_uart);
 
-    // Move the UART into a global so it can be called anywhere, including in panics.
+    // Move the UART into a global so it can be called anywhere, including in panics. Anything printed before now goes first.
+    let lost = msp430::critical_section::with(|cs| {
+        let mut early = EARLY_QUEUE.borrow_ref_mut(cs);
+        while let Some(byte) = early.pop() {
+            nb::block!(debug_uart.write_byte(byte)).ok();
+        }
+        crate::serial::SERIAL.replace(cs, Some(debug_uart));
+        debug_rx.enable_rx_interrupts();
+        DEBUG_RX.replace(cs, Some(debug_rx));
+        EARLY_DROPPED.borrow(cs).get()
+    });
+    if lost > 0 {
+        crate::println!("({} bytes printed before serial init were lost)", lost);
+    }
+}
+
+/// The oldest byte received over the debug UART that hasn't been read yet.
+#[cfg(feature = "console")]
+pub fn read_byte() -> Option<u8> {
+    msp430::critical_section::with(|cs| RX_QUEUE.borrow_ref_mut(cs).pop())
+}
+
+/// Bytes received while we were busy or asleep. Interrupts are only enabled once `lpm::start_clock()` has been called, and
+/// not inside critical sections, so bytes can be lost to overruns. Fine for typing, but don't paste.
+const RX_QUEUE_LEN: usize = 32;
+/// Bytes printed but not sent yet. At 115200 baud this takes about 11ms to send.
+const TX_QUEUE_LEN: usize = 128;
+/// Bytes printed before the UART was configured, e.g. by drivers that log while `board::configure()` sets them up.
+const EARLY_QUEUE_LEN: usize = 64;
+
+struct ByteQueue<const N: usize> {
+    buf: [u8; N],
+    /// Index of the oldest byte.
+    start: usize,
+    len: usize,
+}
+impl<const N: usize> ByteQueue<N> {
+    const fn new() -> Self {
+        Self { buf: [0; N], start: 0, len: 0 }
+    }
+
+    fn is_full(&self) -> bool {
+        self.len == N
+    }
+
+    /// Drops the byte if the queue is full.
+    fn push(&mut self, byte: u8) {
+        if self.is_full() { return }
+        self.buf[(self.start + self.len) % N] = byte;
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 { return None }
+        let byte = self.buf[self.start];
+        self.start = (self.start + 1) % N;
+        self.len -= 1;
+        Some(byte)
+    }
+}
+
+static DEBUG_RX: Mutex<RefCell<Option<DebugRx>>> = Mutex::new(RefCell::new(None));
+static RX_QUEUE: Mutex<RefCell<ByteQueue<RX_QUEUE_LEN>>> = Mutex::new(RefCell::new(ByteQueue::new()));
+static EARLY_QUEUE: Mutex<RefCell<ByteQueue<EARLY_QUEUE_LEN>>> = Mutex::new(RefCell::new(ByteQueue::new()));
+/// Bytes that didn't fit in `EARLY_QUEUE`.
+static EARLY_DROPPED: Mutex<Cell<u16>> = Mutex::new(Cell::new(0));
+
+#[interrupt]
+fn EUSCI_A0() {
+    // Doesn't need to wake us, the console checks the queue regularly and printing doesn't wait for the interrupt
+    msp430::critical_section::with(|cs| {
+        if let Some(rx) = DEBUG_RX.borrow_ref_mut(cs).as_mut() {
+            // Reading clears the interrupt flag, even on errors
+            match rx.read() {
+                Ok(byte) | Err(nb::Error::Other(RecvError::Overrun(byte))) => RX_QUEUE.borrow_ref_mut(cs).push(byte),
+                Err(_) => (),
+            }
+        }
+        if let Some(serial) = SERIAL.borrow_ref_mut(cs).as_mut() {
+            serial.send_next();
+        }
+    });
+}
+
+/// Send raw bytes over the debug UART, e.g. log records (see ilog.rs). If it hasn't been configured yet, they're held
+/// until it is.
+pub fn write_bytes(bytes: &[u8]) {
+    for &byte in bytes {
+        // A critical section per attempt, so interrupts (the GPS in particular) get in while we wait for the UART
+        loop {
+            let written = msp430::critical_section::with(|cs| {
+                match SERIAL.borrow_ref_mut(cs).as_mut() {
+                    Some(serial) => serial.write_byte(byte).is_ok(),
+                    None => {
+                        let mut early = EARLY_QUEUE.borrow_ref_mut(cs);
+                        if early.is_full() {
+                            let dropped = EARLY_DROPPED.borrow(cs);
+                            dropped.set(dropped.get().saturating_add(1));
+                        }
+                        early.push(byte);
+                        true
+                    },
+                }
+            });
+            if written { break }
+        }
+    }
+}
+
+/// Where the print macros write to: the debug UART, or the early queue before it's configured.
+#[cfg_attr(feature = "deferred-log", allow(dead_code))] // Only `stdlib_print!` uses it then
+pub struct Printer;
+impl core::fmt::Write for Printer {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        write_bytes(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// What printing does when the TX queue is full.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Lose the new bytes, so printing never holds anything up. `dropped_bytes()` counts them.
+    #[allow(dead_code)] // Selected in config.rs
+    Drop,
+    /// Wait for the UART to make room, as printing did before it was buffered.
+    Block,
+}
+
+/// Change `config::DEBUG_SERIAL_OVERFLOW` at runtime.
+pub fn set_overflow_policy(policy: OverflowPolicy) {
+    msp430::critical_section::with(|cs| {
+        if let Some(serial) = SERIAL.borrow_ref_mut(cs).as_mut() { serial.policy = policy }
+    });
+}
+
+/// Bytes lost to a full TX queue since boot.
+#[cfg(feature = "console")]
+pub fn dropped_bytes() -> u16 {
+    msp430::critical_section::with(|cs| SERIAL.borrow_ref(cs).as_ref().map_or(0, |serial| serial.dropped))
+}
+
+/// Queue printed bytes for the TX interrupt from now on, instead of sending them straight away. Only once interrupts are
+/// enabled, which `lpm::start_clock()` does.
+pub fn enable_tx_buffering() {
+    msp430::critical_section::with(|cs| BUFFERED.borrow(cs).set(true));
+}
+
+/// Wait until everything printed has been sent. Needed before resetting, or anything else that stops SMCLK.
+pub fn flush() {
     msp430::critical_section::with(|cs| {
-        crate::serial::SERIAL.replace(cs, Some(debug_uart))
+        if let Some(serial) = SERIAL.borrow_ref_mut(cs).as_mut() { serial.flush() }
     });
 }
 
@@ -25,12 +176,76 @@ pub fn configure_debug_serial(pin: DebugTxPin, smclk: &Smclk, debug_eusci: Debug
 // Tx can only print bytes by default, but by implementing this we can print arbitrary (ASCII) strings.
 // Format strings are automatically implemented for implementers of core::fmt::Write, so
 // with the custom println!() macro below this means we get full println! behaviour.
-pub struct PrintableSerial( pub Tx<DebugEusci> );
+//
+// Once `enable_tx_buffering()` has been called, printing only queues the bytes. The TX interrupt sends them, which means
+// a `println!` doesn't hold up a task (with interrupts off) for the milliseconds it takes to go out at 115200 baud.
+// Interrupts are enabled once `lpm::start_clock()` has been called, so the queue empties in the background. A task that
+// prints faster than the UART can send runs into `OverflowPolicy`. `Block` waits one byte at a time, outside any
+// critical section of ours, so GPS bytes still get received while a long dump goes out. Modes that don't use lpm.rs (the
+// ground station, survey and radio tests) never enable interrupts or buffering, so their output goes out as it's printed.
+static BUFFERED: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+
+pub struct PrintableSerial {
+    tx: Tx<DebugEusci>,
+    queue: ByteQueue<TX_QUEUE_LEN>,
+    policy: OverflowPolicy,
+    dropped: u16,
+}
+impl PrintableSerial {
+    fn new(tx: Tx<DebugEusci>) -> Self {
+        Self { tx, queue: ByteQueue::new(), policy: crate::config::DEBUG_SERIAL_OVERFLOW, dropped: 0 }
+    }
+
+    /// `WouldBlock` if the byte has to wait for the UART. Never waits itself, so the caller can let interrupts in first.
+    fn write_byte(&mut self, byte: u8) -> nb::Result<(), Infallible> {
+        use embedded_hal::serial::Write;
+        if !msp430::critical_section::with(|cs| BUFFERED.borrow(cs).get()) {
+            return self.tx.write(byte).map_err(|_| nb::Error::WouldBlock);
+        }
+        if self.queue.is_full() {
+            match self.policy {
+                OverflowPolicy::Drop => {
+                    self.dropped = self.dropped.saturating_add(1);
+                    return Ok(());
+                },
+                // Make room ourselves rather than wait for the interrupt, as we may have been called with interrupts off
+                OverflowPolicy::Block => {
+                    self.tx.flush().map_err(|_| nb::Error::WouldBlock)?;
+                    if let Some(oldest) = self.queue.pop() { self.tx.write(oldest).ok(); }
+                },
+            }
+        }
+        self.queue.push(byte);
+        self.tx.enable_tx_interrupts();
+        Ok(())
+    }
+
+    /// Send the next byte if the UART is ready for it. Called from the interrupt.
+    fn send_next(&mut self) {
+        use embedded_hal::serial::Write;
+        if self.tx.flush().is_err() { return } // Still sending. This interrupt was for RX.
+        match self.queue.pop() {
+            Some(byte) => { self.tx.write(byte).ok(); },
+            None => self.tx.disable_tx_interrupts(), // Otherwise it keeps firing while the UART is idle
+        }
+    }
+
+    fn flush(&mut self) {
+        use embedded_hal::serial::Write;
+        while let Some(byte) = self.queue.pop() {
+            nb::block!(self.tx.write(byte)).ok();
+        }
+        nb::block!(self.tx.flush()).ok();
+        // TXIFG only means the last byte has moved to the shift register. Wait for it to go out before, say, a reset.
+        let uca0 = unsafe { &*E_USCI_A0::ptr() };
+        while uca0.uca0statw().read().ucbusy().bit_is_set() {}
+        self.tx.disable_tx_interrupts();
+    }
+}
 impl core::fmt::Write for PrintableSerial {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
         for char in s.chars() {
-            use embedded_hal::serial::Write;
-            nb::block!( self.0.write(char as u8) ).ok(); // The cast to u8 assumes ASCII-only characters
+            nb::block!(self.write_byte(char as u8)).ok(); // The cast to u8 assumes ASCII-only characters
         }
         Ok(())
     }
@@ -38,60 +253,71 @@ impl core::fmt::Write for PrintableSerial {
 
 // Store our serial handle globally after it's been configured, so we don't have to carry it around with us everywhere.
 use msp430::interrupt::Mutex;
-use core::cell::RefCell;
+use core::{cell::{Cell, RefCell}, convert::Infallible};
 
-use crate::pin_mappings::{DebugEusci, DebugTxPin};
+use crate::pin_mappings::{DebugEusci, DebugRx, DebugRxPin, DebugTxPin};
 /// Used by println macros to print over UART.
 pub static SERIAL: Mutex<RefCell<Option< PrintableSerial >>> = Mutex::new(RefCell::new(None));
 
 // Make a macro equivalent to the regular println!() macro.
-/// Prints over `eUSCI_A0` serial. Panics if `board::configure()` hasn't been called yet.
+/// Prints over `eUSCI_A0` serial. Before `board::configure()` sets it up, output is held (up to `EARLY_QUEUE_LEN` bytes) and sent once it has.
+#[cfg(not(feature = "deferred-log"))]
 #[macro_export]
 macro_rules! print {
     ($first:tt $(, $( $rest:tt )* )?) => {
         {
-            msp430::critical_section::with(|cs| {
-                use ufmt::uwrite;
-                match *$crate::serial::SERIAL.borrow_ref_mut(cs) {
-                    Some(ref mut serial) => uwrite!(ufmt_utils::WriteAdapter(serial), $first,  $( $($rest)* )*).ok(),
-                    None => panic!("Printing without serial configuration"),
-                }
-            });
+            use ufmt::uwrite;
+            uwrite!(ufmt_utils::WriteAdapter($crate::serial::Printer), $first,  $( $($rest)* )*).ok();
         }
     };
 }
 
-/// Prints over `eUSCI_A0` serial. Fails to print if `board::configure()` hasn't been called yet.
+/// Prints over `eUSCI_A0` serial. Before `board::configure()` sets it up, output is held (up to `EARLY_QUEUE_LEN` bytes) and sent once it has.
+#[cfg(not(feature = "deferred-log"))]
 #[macro_export]
 macro_rules! println {
     ($first:tt $(, $( $rest:tt )* )?) => {
         {
-            $crate::print!($first,  $( $($rest)* )*);
-            $crate::print!("\n");
+            use ufmt::uwriteln;
+            uwriteln!(ufmt_utils::WriteAdapter($crate::serial::Printer), $first,  $( $($rest)* )*).ok();
         }
     };
 }
 
+// With `deferred-log`, printing sends a record with the format string interned, like the log macros. See ilog.rs.
+/// Prints over `eUSCI_A0` serial, interned. Before `board::configure()` sets it up, output is held (up to `EARLY_QUEUE_LEN` bytes) and sent once it has.
+#[cfg(feature = "deferred-log")]
+#[macro_export]
+macro_rules! print {
+    ($fmt:literal $(, $arg:expr)* $(,)?) => {
+        $crate::ilog!(@send $crate::ilog::PRINT, concat!(module_path!(), "|", $fmt) $(, $arg)*)
+    };
+}
+
+/// Prints over `eUSCI_A0` serial, interned. Before `board::configure()` sets it up, output is held (up to `EARLY_QUEUE_LEN` bytes) and sent once it has.
+#[cfg(feature = "deferred-log")]
+#[macro_export]
+macro_rules! println {
+    ($fmt:literal $(, $arg:expr)* $(,)?) => {
+        $crate::ilog!(@send $crate::ilog::PRINT, concat!(module_path!(), "|", $fmt, "\n") $(, $arg)*)
+    };
+}
+
 // Make a macro equivalent to the regular println!() macro.
-/// Prints over `eUSCI_A0` serial. Panics if `board::configure()` hasn't been called yet.
+/// Prints over `eUSCI_A0` serial. Before `board::configure()` sets it up, output is held (up to `EARLY_QUEUE_LEN` bytes) and sent once it has.
 /// 
 /// This macro uses the Rust core library, which can print more things but bloats the executable. Avoid using this if you can.
 #[macro_export]
 macro_rules! stdlib_print {
     ($first:tt $(, $( $rest:tt )* )?) => {
         {
-            msp430::critical_section::with(|cs| {
-                use core::fmt::Write;
-                match *$crate::serial::SERIAL.borrow_ref_mut(cs) {
-                    Some(ref mut serial) => write!(serial, $first,  $( $($rest)* )*).ok(),
-                    None => panic!("Printing without serial configuration"),
-                };
-            });
+            use core::fmt::Write;
+            write!($crate::serial::Printer, $first,  $( $($rest)* )*).ok();
         }
     };
 }
 
-/// Prints over `eUSCI_A0` serial. Fails to print if `board::configure()` hasn't been called yet.
+/// Prints over `eUSCI_A0` serial. Before `board::configure()` sets it up, output is held (up to `EARLY_QUEUE_LEN` bytes) and sent once it has.
 /// 
 /// This macro uses the Rust core library, which can print more things but bloats the executable. Avoid using this if you can.
 #[macro_export]
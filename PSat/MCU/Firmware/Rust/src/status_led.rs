@@ -0,0 +1,94 @@
+// Shows what the payload is doing on the RGB LED, without blocking.
+//
+// `update()` works out the colour from the time, so call it regularly (every 100ms or so, from a periodic task) and
+// the patterns play out in the background:
+//
+// | State          | Pattern                                     |
+// |----------------|---------------------------------------------|
+// | Searching      | Short blue blink every second               |
+// | Fix acquired   | Short green blink every 2 seconds           |
+// | Transmitting   | Solid cyan                                  |
+// | Fault          | The fault's blink code, see `blink_code.rs` |
+//
+// Faults (which include a low battery) take priority over everything else. Even short blinks cost power, so `config::STATUS_LED_MODE` can limit the
+// LED to faults or turn it off completely for flight.
+
+use crate::{blink_code::{BlinkCode, Colour, RgbLed}, config, lpm};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatusLedMode {
+    #[allow(dead_code)] // Selected in config.rs
+    Off,
+    /// Only show blink codes.
+    FaultsOnly,
+    #[allow(dead_code)]
+    All,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Activity {
+    /// Waiting for the GPS to get a fix.
+    Searching,
+    FixAcquired,
+    Transmitting,
+}
+
+/// A colour that's on for `on_ms` out of every `period_ms`.
+struct Pattern {
+    colour: Colour,
+    on_ms: u32,
+    period_ms: u32,
+}
+impl Pattern {
+    fn colour_at(&self, ms: u32) -> Colour {
+        if ms % self.period_ms < self.on_ms { self.colour } else { Colour::Off }
+    }
+}
+
+const SEARCHING: Pattern = Pattern { colour: Colour::Blue, on_ms: 50, period_ms: 1000 };
+const FIX_ACQUIRED: Pattern = Pattern { colour: Colour::Green, on_ms: 50, period_ms: 2000 };
+const TRANSMITTING: Pattern = Pattern { colour: Colour::Cyan, on_ms: 1, period_ms: 1 };
+
+pub struct StatusLed {
+    activity: Activity,
+    fault: Option<BlinkCode>,
+    /// When the pattern being shown started, from `lpm::now()`. Patterns start from the beginning when they change.
+    since: u32,
+}
+impl StatusLed {
+    pub fn new() -> Self {
+        Self { activity: Activity::Searching, fault: None, since: lpm::now() }
+    }
+
+    pub fn set_activity(&mut self, activity: Activity) {
+        if activity != self.activity {
+            self.activity = activity;
+            self.since = lpm::now();
+        }
+    }
+
+    /// Set the most important current problem, or `None` once there isn't one.
+    pub fn set_fault(&mut self, fault: Option<BlinkCode>) {
+        if fault != self.fault {
+            self.fault = fault;
+            self.since = lpm::now();
+        }
+    }
+
+    /// Set the LED to the right colour for now.
+    pub fn update(&self, leds: &mut impl RgbLed) {
+        let ms = lpm::ticks_to_ms(lpm::now().wrapping_sub(self.since));
+        // Matching on the config constant rather than a field, so the patterns for modes we're not using aren't linked in
+        let colour = match (config::STATUS_LED_MODE, self.fault) {
+            (StatusLedMode::Off, _) => Colour::Off,
+            (_, Some(fault)) => fault.colour_at(ms),
+            (StatusLedMode::FaultsOnly, None) => Colour::Off,
+            (StatusLedMode::All, None) => match self.activity {
+                Activity::Searching => SEARCHING.colour_at(ms),
+                Activity::FixAcquired => FIX_ACQUIRED.colour_at(ms),
+                Activity::Transmitting => TRANSMITTING.colour_at(ms),
+            },
+        };
+        leds.set_colour(colour);
+    }
+}
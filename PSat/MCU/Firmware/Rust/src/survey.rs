@@ -0,0 +1,88 @@
+// RF survey mode. Sweeps the radio across a range of frequencies and prints the received signal strength at each step,
+// so you can check for interference before launch. Call `survey::run(board, SurveyConfig::default())` from `main()`.
+//
+// Output is one table per sweep, easy to paste into a spreadsheet:
+// Freq (kHz) | Avg (dBm) | Max (dBm)
+// 902000     | -118      | -112
+
+#![allow(dead_code)]
+use core::convert::Infallible;
+use embedded_lora_rfm95::lora::types::Bandwidth;
+use msp430fr2x5x_hal::hal::blocking::delay::DelayMs;
+use ufmt::derive::uDebug;
+use crate::{board::Board, error, lora::RadioMode, println};
+
+pub struct SurveyConfig {
+    pub start_hz: u32,
+    pub stop_hz: u32,
+    pub step_hz: u32,
+    /// RSSI readings taken at each step. More samples catch more intermittent transmitters, but slow the sweep down.
+    pub samples_per_step: u8,
+    /// Measurement bandwidth. Wider bandwidths see more of the spectrum per step, at the cost of a higher noise floor.
+    pub bandwidth: Bandwidth,
+}
+impl Default for SurveyConfig {
+    /// Covers the 915MHz ISM band.
+    fn default() -> Self {
+        Self { start_hz: 902_000_000, stop_hz: 928_000_000, step_hz: 500_000, samples_per_step: 16, bandwidth: Bandwidth::B500 }
+    }
+}
+impl SurveyConfig {
+    pub fn validate(&self) -> Result<(), InvalidSurveyConfig> {
+        if self.step_hz == 0 { return Err(InvalidSurveyConfig::ZeroStep) }
+        if self.start_hz > self.stop_hz { return Err(InvalidSurveyConfig::StartAboveStop) }
+        Ok(())
+    }
+}
+
+#[derive(Debug, uDebug, Clone, Copy, PartialEq, Eq)]
+pub enum InvalidSurveyConfig {
+    ZeroStep,
+    StartAboveStop,
+}
+
+/// Time for the receiver to settle after retuning before RSSI readings are valid.
+const SETTLE_MS: u16 = 2;
+
+/// Sweep forever. Only returns if `config` doesn't make sense.
+pub fn run(mut board: Board, config: SurveyConfig) -> Result<Infallible, InvalidSurveyConfig> {
+    if let Err(e) = config.validate() {
+        error!("Can't survey: {:?}", e);
+        return Err(e);
+    }
+    board.radio.set_mode(RadioMode::Standby).unwrap();
+    board.radio.driver.set_bandwidth(config.bandwidth).unwrap();
+
+    let samples = config.samples_per_step.max(1);
+    let mut sweep: u16 = 0;
+    loop {
+        println!("Sweep {}", sweep);
+        println!("Freq (kHz) | Avg (dBm) | Max (dBm)");
+
+        let mut freq_hz = config.start_hz;
+        while freq_hz <= config.stop_hz {
+            board.radio.set_mode(RadioMode::Standby).unwrap();
+            board.radio.set_frequency(freq_hz).unwrap();
+            board.radio.set_mode(RadioMode::RxContinuous).unwrap();
+            board.delay.delay_ms(SETTLE_MS);
+
+            let mut sum: i32 = 0;
+            let mut max = i16::MIN;
+            for _ in 0..samples {
+                let rssi = board.radio.current_rssi().unwrap();
+                sum += rssi as i32;
+                max = max.max(rssi);
+                board.delay.delay_ms(1);
+            }
+            println!("{}     | {}      | {}", freq_hz / 1000, sum / samples as i32, max);
+
+            // Stop rather than wrap around if the step goes past the top of the u32 range
+            let Some(next) = freq_hz.checked_add(config.step_hz) else { break };
+            freq_hz = next;
+        }
+
+        board.radio.set_mode(RadioMode::Standby).unwrap();
+        board.gpio.green_led.toggle();
+        sweep = sweep.wrapping_add(1);
+    }
+}
@@ -0,0 +1,61 @@
+// Time-division multiple access: lets several payloads beacon on the same channel without talking over eachother.
+//
+// GPS time is divided into fixed-length slots, numbered 0..num_slots and repeating forever. Each payload
+// only transmits at the start of the slot matching its ID. Because every payload gets its time from GPS,
+// no coordination between payloads is required.
+
+#![allow(dead_code)]
+use crate::gps::UtcTime;
+
+const MILLIS_PER_DAY: u32 = 24 * 60 * 60 * 1000;
+
+#[derive(Clone, Copy)]
+pub struct SlotSchedule {
+    /// Number of payloads that can share the channel. Payload `n` uses slot `n % num_slots`.
+    pub num_slots: u8,
+    /// Should be longer than the airtime of a beacon plus `guard_ms`.
+    pub slot_len_ms: u32,
+    /// How late into our slot we're still allowed to start transmitting. Accounts for GPS message latency.
+    pub guard_ms: u32,
+}
+impl SlotSchedule {
+    /// Which slot `payload_id` transmits in.
+    pub fn slot_for(&self, payload_id: u8) -> u8 {
+        payload_id % self.num_slots
+    }
+
+    /// The slot that is active at `time`.
+    pub fn current_slot(&self, time: &UtcTime) -> u8 {
+        ((time.millis_of_day() / self.slot_len_ms) % self.num_slots as u32) as u8
+    }
+
+    /// The length of a full cycle through every slot, i.e. the time between beacons for each payload.
+    pub fn cycle_len_ms(&self) -> u32 {
+        self.slot_len_ms * self.num_slots as u32
+    }
+
+    /// Whether `payload_id` may start a transmission at `time`.
+    pub fn may_transmit(&self, payload_id: u8, time: &UtcTime) -> bool {
+        let into_slot = time.millis_of_day() % self.slot_len_ms;
+        self.current_slot(time) == self.slot_for(payload_id) && into_slot <= self.guard_ms
+    }
+
+    /// Milliseconds from `time` until the end of the slot it's in.
+    pub fn millis_left_in_slot(&self, time: &UtcTime) -> u32 {
+        self.slot_len_ms - time.millis_of_day() % self.slot_len_ms
+    }
+
+    /// Milliseconds from `time` until the start of the next slot belonging to `payload_id`.
+    pub fn millis_until_slot(&self, payload_id: u8, time: &UtcTime) -> u32 {
+        let now = time.millis_of_day();
+        let cycle = self.cycle_len_ms();
+        let slot_start = self.slot_for(payload_id) as u32 * self.slot_len_ms;
+        let into_cycle = now % cycle;
+
+        let wait = if into_cycle <= slot_start { slot_start - into_cycle } else { cycle - into_cycle + slot_start };
+
+        // Cycles don't necessarily divide evenly into a day, so the schedule restarts at midnight.
+        let until_midnight = MILLIS_PER_DAY - now;
+        if wait > until_midnight { until_midnight + slot_start } else { wait }
+    }
+}
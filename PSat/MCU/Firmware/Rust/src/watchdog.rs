@@ -0,0 +1,96 @@
+// The hardware watchdog. Once started, it resets the MCU unless it's fed within `config::WATCHDOG_PERIOD`.
+//
+// Rather than feeding it from wherever is convenient, each task that should be making progress registers for a `CheckIn`,
+// and the watchdog is only fed once every registered task has checked in since the last feed. So a task that is stuck or
+// starved of CPU time causes a reset, as does the whole program hanging (e.g. on an `nb::block!` that never completes).
+//
+// It runs from ACLK, which keeps going in LPM3, so long sleeps have to feed it. See `Board::sleep_s()`.
+
+use embedded_hal::watchdog::{Watchdog as _, WatchdogEnable};
+use msp430fr2355::WDT_A;
+use msp430fr2x5x_hal::watchdog::{WatchdogMode, Wdt, WdtClkPeriods};
+use crate::lpm;
+
+/// A task's handle for checking in with the watchdog.
+#[derive(Clone, Copy)]
+pub struct CheckIn(u8);
+
+pub struct Watchdog {
+    wdt: Wdt<WatchdogMode>,
+    period: Option<WdtClkPeriods>,
+    /// One bit per `CheckIn`.
+    registered: u8,
+    checked_in: u8,
+}
+impl Watchdog {
+    /// Takes a halted watchdog, already clocked from ACLK. It stays halted until `start()`.
+    pub fn new(wdt: Wdt<WatchdogMode>) -> Self {
+        Self { wdt, period: None, registered: 0, checked_in: 0 }
+    }
+
+    /// Start the watchdog. From now on it must be fed at least once every `period` cycles of ACLK (32768 Hz).
+    pub fn start(&mut self, period: WdtClkPeriods) {
+        self.period = Some(period);
+        self.wdt.start(period);
+    }
+
+    #[cfg(feature = "console")]
+    pub fn is_running(&self) -> bool {
+        self.period.is_some()
+    }
+
+    /// Add a task that must check in before each feed. Panics after 8 tasks.
+    pub fn register(&mut self) -> CheckIn {
+        let index = self.registered.count_ones() as u8;
+        assert!(index < 8, "Too many watchdog check-ins");
+        self.registered |= 1 << index;
+        CheckIn(index)
+    }
+
+    /// Report that a task is making progress.
+    pub fn check_in(&mut self, task: CheckIn) {
+        self.checked_in |= 1 << task.0;
+    }
+
+    /// Feed the watchdog if every registered task has checked in since the last feed. Call this more often than the
+    /// watchdog period, e.g. once a second.
+    pub fn feed_if_all_checked_in(&mut self) -> bool {
+        if self.checked_in & self.registered != self.registered { return false }
+
+        self.checked_in = 0;
+        self.wdt.feed();
+        true
+    }
+
+    /// Feed the watchdog regardless of check-ins. Only for when registered tasks can't run, such as during a long sleep.
+    pub fn feed(&mut self) {
+        self.wdt.feed();
+    }
+
+    /// The longest we can safely go between feeds, leaving a good margin. `None` if the watchdog isn't running.
+    pub fn feed_interval_ms(&self) -> Option<u32> {
+        let cycles: u32 = match self.period? {
+            WdtClkPeriods::_64 => 64,
+            WdtClkPeriods::_512 => 512,
+            WdtClkPeriods::_8192 => 8192,
+            WdtClkPeriods::_32K => 32 * 1024,
+            WdtClkPeriods::_512K => 512 * 1024,
+            WdtClkPeriods::_8192K => 8192 * 1024,
+            WdtClkPeriods::_128M | WdtClkPeriods::_2G => u32::MAX,
+        };
+        Some(lpm::ticks_to_ms(cycles / 2))
+    }
+}
+
+/// Stop the watchdog, wherever it was started from. For the panic handler, which doesn't have a `Watchdog`.
+pub fn hold_now() {
+    const WDTPW: u16 = 0x5A00;
+    const WDTHOLD: u16 = 0x0080;
+    unsafe { &*WDT_A::ptr() }.wdtctl.modify(|r, w| unsafe { w.bits(WDTPW | WDTHOLD | r.bits() & 0x00FF) });
+}
+
+/// Reset the MCU straight away, by writing to the watchdog without its password. Shows up as `ResetCause::WatchdogPassword`.
+pub fn reset_now() -> ! {
+    unsafe { &*WDT_A::ptr() }.wdtctl.write(|w| unsafe { w.bits(0) });
+    loop { msp430::asm::barrier(); }
+}
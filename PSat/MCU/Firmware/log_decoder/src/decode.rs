@@ -0,0 +1,252 @@
+// Turns the debug UART stream back into text. Log records (see ../Rust/src/ilog.rs for the format) are looked up in the
+// firmware's `.ilog` section and formatted; everything else is passed through as it is. Records from `print!` and
+// `println!` (level 0) are printed as they are, without the level and module.
+
+use std::{fmt, io::{self, Write}};
+
+const RECORD_START: u8 = 0xFE;
+/// The level of records from `print!` and `println!`, the same as `ilog::PRINT`.
+const PRINT: u8 = 0;
+
+/// Argument types, the same as `ilog::tag`.
+mod tag {
+    pub const U8: u8 = 1;
+    pub const U16: u8 = 2;
+    pub const U32: u8 = 3;
+    pub const I8: u8 = 4;
+    pub const I16: u8 = 5;
+    pub const I32: u8 = 6;
+    pub const BOOL: u8 = 7;
+    pub const STR: u8 = 8;
+}
+
+enum State {
+    Text,
+    /// Got `RECORD_START`, waiting for the length.
+    Length,
+    /// Reading a record this long.
+    Record(usize),
+}
+
+pub struct Decoder<'a> {
+    /// Where the `.ilog` section starts. String IDs are addresses, so this is subtracted to find them in `strings`.
+    strings_address: u32,
+    /// The `.ilog` section.
+    strings: &'a [u8],
+    state: State,
+    record: Vec<u8>,
+}
+impl<'a> Decoder<'a> {
+    pub fn new(strings_address: u32, strings: &'a [u8]) -> Self {
+        Self { strings_address, strings, state: State::Text, record: Vec::new() }
+    }
+
+    /// Handle the next byte from the UART, writing any text that's ready to `out`.
+    pub fn push(&mut self, byte: u8, out: &mut impl Write) -> io::Result<()> {
+        match self.state {
+            State::Text if byte == RECORD_START => self.state = State::Length,
+            State::Text => out.write_all(&[byte])?,
+            State::Length if byte == 0 => self.state = State::Text,
+            State::Length => {
+                self.record.clear();
+                self.state = State::Record(byte as usize);
+            },
+            State::Record(len) => {
+                self.record.push(byte);
+                if self.record.len() == len {
+                    self.state = State::Text;
+                    match self.format(&self.record) {
+                        Ok(text) => out.write_all(text.as_bytes())?,
+                        Err(e) => writeln!(out, "<bad log record {:02X?}: {e}>", self.record)?,
+                    }
+                    out.flush()?;
+                }
+            },
+        }
+        Ok(())
+    }
+
+    fn format(&self, record: &[u8]) -> Result<String, &'static str> {
+        let [level, lo, hi, args @ ..] = record else { return Err("too short") };
+        let level = match *level {
+            PRINT => None,
+            1 => Some('E'),
+            2 => Some('W'),
+            3 => Some('I'),
+            4 => Some('D'),
+            5 => Some('T'),
+            _ => return Err("unknown level"),
+        };
+        let id = u16::from_le_bytes([*lo, *hi]) as u32;
+        let offset = id.checked_sub(self.strings_address).ok_or("string ID outside .ilog, is this the right ELF?")? as usize;
+        let string = self.strings.get(offset..).ok_or("string ID outside .ilog, is this the right ELF?")?;
+        let string = string.split(|b| *b == 0).next().unwrap_or_default();
+        let string = std::str::from_utf8(string).map_err(|_| "string isn't UTF-8, is this the right ELF?")?;
+        let (module, template) = string.split_once('|').ok_or("not a log string, is this the right ELF?")?;
+        // The same as `log::module_name()`
+        let module = module.split_once("::").map_or("main", |(_, module)| module);
+
+        let mut args = args;
+        let mut values = Vec::new();
+        while !args.is_empty() {
+            let (value, rest) = Value::parse(args)?;
+            values.push(value);
+            args = rest;
+        }
+        let text = fill(template, &values);
+        Ok(match level {
+            Some(level) => format!("[{level} {module}] {text}\n"),
+            None => text,
+        })
+    }
+}
+
+enum Value {
+    Int(i64),
+    Bool(bool),
+    Str(String),
+}
+impl Value {
+    /// Parse the first argument, returning it and the rest.
+    fn parse(args: &[u8]) -> Result<(Value, &[u8]), &'static str> {
+        let (&tag, rest) = args.split_first().ok_or("missing argument")?;
+        let len = match tag {
+            tag::U8 | tag::I8 | tag::BOOL => 1,
+            tag::U16 | tag::I16 => 2,
+            tag::U32 | tag::I32 => 4,
+            tag::STR => 1 + *rest.first().ok_or("truncated argument")? as usize,
+            _ => return Err("unknown argument type"),
+        };
+        let bytes = rest.get(..len).ok_or("truncated argument")?;
+        let mut le = [0u8; 4];
+        le[..len.min(4)].copy_from_slice(&bytes[..len.min(4)]);
+        let value = match tag {
+            tag::U8 | tag::U16 | tag::U32 => Value::Int(u32::from_le_bytes(le) as i64),
+            tag::I8 => Value::Int(bytes[0] as i8 as i64),
+            tag::I16 => Value::Int(i16::from_le_bytes([bytes[0], bytes[1]]) as i64),
+            tag::I32 => Value::Int(i32::from_le_bytes(le) as i64),
+            tag::BOOL => Value::Bool(bytes[0] != 0),
+            _ => Value::Str(String::from_utf8_lossy(&bytes[1..]).into_owned()),
+        };
+        Ok((value, &rest[len..]))
+    }
+}
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Int(i) => write!(f, "{i}"),
+            Value::Bool(b) => write!(f, "{b}"),
+            Value::Str(s) => write!(f, "{s}"),
+        }
+    }
+}
+
+/// Replace each `{...}` in a format string with the next value. Values the payload couldn't fit in the record show
+/// as `?`.
+fn fill(template: &str, values: &[Value]) -> String {
+    let mut out = String::new();
+    let mut values = values.iter();
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => { chars.next(); out.push('{') },
+            '}' if chars.peek() == Some(&'}') => { chars.next(); out.push('}') },
+            '{' => {
+                for c in chars.by_ref() {
+                    if c == '}' { break }
+                }
+                match values.next() {
+                    Some(value) => out.push_str(&value.to_string()),
+                    None => out.push('?'),
+                }
+            },
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Two strings, starting at address 1 like the firmware's `.ilog`.
+    const STRINGS: &[u8] = b"apss::gps|Fix {} at {:?}m\0apss|{{literal}} {}\0";
+    const FIX: u16 = 1;
+    const LITERAL: u16 = 1 + 26;
+
+    fn decode(input: &[u8]) -> String {
+        let mut decoder = Decoder::new(1, STRINGS);
+        let mut out = Vec::new();
+        for byte in input {
+            decoder.push(*byte, &mut out).unwrap();
+        }
+        String::from_utf8(out).unwrap()
+    }
+
+    /// A record for `string` at `level`, with the length filled in.
+    fn record(level: u8, string: u16, args: &[u8]) -> Vec<u8> {
+        let [lo, hi] = string.to_le_bytes();
+        let mut record = vec![RECORD_START, (3 + args.len()) as u8, level, lo, hi];
+        record.extend_from_slice(args);
+        record
+    }
+
+    #[test]
+    fn text_passes_through() {
+        assert_eq!(decode(b"Hello world!\n> "), "Hello world!\n> ");
+    }
+
+    #[test]
+    fn full_record() {
+        let mut input = b"before\n".to_vec();
+        input.extend(record(3, FIX, &[tag::BOOL, 1, tag::I32, 0x18, 0xFC, 0xFF, 0xFF]));
+        input.extend(b"after\n");
+        assert_eq!(decode(&input), "before\n[I gps] Fix true at -1000m\nafter\n");
+    }
+
+    #[test]
+    fn string_argument() {
+        let input = record(2, LITERAL, &[tag::STR, 3, b'a', b'b', b'c']);
+        assert_eq!(decode(&input), "[W main] {literal} abc\n");
+    }
+
+    #[test]
+    fn missing_args_show_as_question_marks() {
+        // The payload leaves off arguments that don't fit in a record
+        assert_eq!(decode(&record(4, FIX, &[tag::U8, 7])), "[D gps] Fix 7 at ?m\n");
+    }
+
+    #[test]
+    fn truncated_arg_is_an_error() {
+        let output = decode(&record(1, FIX, &[tag::U32, 1, 2]));
+        assert!(output.starts_with("<bad log record"), "{output}");
+        assert!(output.contains("truncated argument"), "{output}");
+    }
+
+    #[test]
+    fn print_records_have_no_prefix() {
+        let mut input = record(PRINT, LITERAL, &[tag::U16, 0x34, 0x12]);
+        input.extend(record(PRINT, LITERAL, &[tag::BOOL, 0]));
+        assert_eq!(decode(&input), "{literal} 4660{literal} false");
+    }
+
+    #[test]
+    fn unknown_level_is_an_error() {
+        let output = decode(&record(9, FIX, &[]));
+        assert!(output.contains("unknown level"), "{output}");
+    }
+
+    #[test]
+    fn string_outside_section_is_an_error() {
+        let output = decode(&record(1, 0, &[]));
+        assert!(output.contains("outside .ilog"), "{output}");
+    }
+
+    #[test]
+    fn fill_handles_escapes_and_specifiers() {
+        let values = [Value::Int(-5), Value::Bool(false), Value::Str("x".into())];
+        assert_eq!(fill("{{a}} {} {:?} {:#?} }}", &values), "{a} -5 false x }");
+        assert_eq!(fill("{} {}", &values[..1]), "-5 ?");
+    }
+}
@@ -0,0 +1,62 @@
+// Just enough of an ELF reader to pull one section out of the firmware. The MSP430 uses 32-bit little-endian ELF.
+
+use std::fmt;
+
+#[derive(Debug)]
+pub enum ElfError {
+    NotElf,
+    /// Only 32-bit little-endian files are supported.
+    WrongClass,
+    Truncated,
+    NoSection(&'static str),
+}
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ElfError::NotElf => write!(f, "not an ELF file"),
+            ElfError::WrongClass => write!(f, "not a 32-bit little-endian ELF file"),
+            ElfError::Truncated => write!(f, "file is truncated"),
+            ElfError::NoSection(name) => write!(f, "no {name} section, was the firmware built with `--features deferred-log`?"),
+        }
+    }
+}
+
+fn u16_at(data: &[u8], offset: usize) -> Result<u16, ElfError> {
+    let bytes = data.get(offset..offset + 2).ok_or(ElfError::Truncated)?;
+    Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Result<u32, ElfError> {
+    let bytes = data.get(offset..offset + 4).ok_or(ElfError::Truncated)?;
+    Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// The address and contents of the section called `name`.
+pub fn section<'a>(elf: &'a [u8], name: &'static str) -> Result<(u32, &'a [u8]), ElfError> {
+    if elf.get(0..4) != Some(b"\x7fELF") { return Err(ElfError::NotElf) }
+    if elf.get(4..6) != Some(&[1, 1]) { return Err(ElfError::WrongClass) } // ELFCLASS32, ELFDATA2LSB
+
+    let table_offset = u32_at(elf, 0x20)? as usize;
+    let entry_size = u16_at(elf, 0x2E)? as usize;
+    let count = u16_at(elf, 0x30)? as usize;
+    let names_index = u16_at(elf, 0x32)? as usize;
+
+    // (name offset, address, file offset, size) of each section
+    let header = |i: usize| -> Result<(usize, u32, usize, usize), ElfError> {
+        let start = table_offset + i * entry_size;
+        Ok((u32_at(elf, start)? as usize, u32_at(elf, start + 0x0C)?, u32_at(elf, start + 0x10)? as usize, u32_at(elf, start + 0x14)? as usize))
+    };
+    let contents = |offset: usize, size: usize| elf.get(offset..offset + size).ok_or(ElfError::Truncated);
+
+    let (_, _, names_offset, names_size) = header(names_index)?;
+    let names = contents(names_offset, names_size)?;
+    for i in 0..count {
+        let (name_offset, address, offset, size) = header(i)?;
+        let section_name = names.get(name_offset..).ok_or(ElfError::Truncated)?;
+        let section_name = section_name.split(|b| *b == 0).next().unwrap_or_default();
+        if section_name == name.as_bytes() {
+            return Ok((address, contents(offset, size)?));
+        }
+    }
+    Err(ElfError::NoSection(name))
+}
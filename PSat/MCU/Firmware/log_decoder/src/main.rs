@@ -0,0 +1,56 @@
+// Decodes logs from firmware built with `--features deferred-log` (see ../Rust/src/ilog.rs). Give it the ELF that was
+// flashed, and the debug UART on stdin or as a file:
+//
+//     stty -F /dev/ttyUSB0 115200 raw
+//     cargo run --release -- ../Rust/target/msp430-none-elf/release/apss_mcu_pcb_firmware < /dev/ttyUSB0
+//
+// Log records are printed as text, the same as a build without `deferred-log` would have printed them. Anything else
+// the payload sends is passed straight through.
+
+mod decode;
+mod elf;
+
+use std::{env, fs::File, io::{self, BufReader, Read}, process::ExitCode};
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 2 || args.len() > 3 {
+        eprintln!("Usage: {} <firmware ELF> [captured UART output, default stdin]", args[0]);
+        return ExitCode::FAILURE;
+    }
+    let elf = match std::fs::read(&args[1]) {
+        Ok(elf) => elf,
+        Err(e) => {
+            eprintln!("Couldn't read {}: {e}", args[1]);
+            return ExitCode::FAILURE;
+        },
+    };
+    let (strings_address, strings) = match elf::section(&elf, ".ilog") {
+        Ok(section) => section,
+        Err(e) => {
+            eprintln!("{}: {e}", args[1]);
+            return ExitCode::FAILURE;
+        },
+    };
+    let input: Box<dyn Read> = match args.get(2) {
+        Some(path) => match File::open(path) {
+            Ok(file) => Box::new(file),
+            Err(e) => {
+                eprintln!("Couldn't open {path}: {e}");
+                return ExitCode::FAILURE;
+            },
+        },
+        None => Box::new(io::stdin()),
+    };
+
+    let mut decoder = decode::Decoder::new(strings_address, strings);
+    let mut out = io::stdout().lock();
+    for byte in BufReader::new(input).bytes() {
+        let result = byte.and_then(|byte| decoder.push(byte, &mut out));
+        if let Err(e) = result {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        }
+    }
+    ExitCode::SUCCESS
+}
@@ -0,0 +1,241 @@
+// LoRa-APRS position reports, for payloads flying on the 70cm amateur band. These are picked up by the existing network
+// of LoRa-APRS iGates and end up on aprs.fi etc. without needing our own ground station.
+// The firmware selects this with `config::BEACON_FORMAT` and sets the radio up in its own aprs.rs. Needs a licensed
+// callsign in `config::APRS_CALLSIGN`, and a 433 MHz radio module.
+//
+// A packet is a 3 byte LoRa-APRS prefix followed by a plain text APRS packet in TNC2 format:
+//
+//     <\xFF\x01N0CALL-11>APZPSA:!/5L!!<*e7OS]Q PSat
+//
+// The position uses the compressed format from the APRS 1.01 spec (chapter 9), which is shorter on air and doesn't lose
+// precision. Everything is integer maths, as the MSP430 has no FPU.
+
+use arrayvec::ArrayVec;
+
+/// Every LoRa-APRS packet starts with these bytes.
+const LORA_APRS_PREFIX: [u8; 3] = [b'<', 0xFF, 0x01];
+/// APRS 'tocall', identifies the software that made the packet. APZxxx is reserved for experimental software.
+const DESTINATION: &str = "APZPSA";
+/// Symbol table and code. '/O' is a balloon.
+const SYMBOL_TABLE: u8 = b'/';
+const SYMBOL_CODE: u8 = b'O';
+
+/// Frequency used by the LoRa-APRS network in most of the world.
+pub const LORA_APRS_FREQ_HZ: u32 = 433_775_000;
+
+pub const MAX_PACKET_LEN: usize = 100;
+
+/// What we know about our position, from whichever NMEA sentence we have.
+pub struct Position {
+    pub latitude_microdegrees: i32,
+    pub longitude_microdegrees: i32,
+    /// Only available from GGA.
+    pub altitude_decimetres: Option<i32>,
+    /// Course in tenths of a degree and speed in tenths of a knot. Only available from RMC.
+    pub course_speed: Option<(u16, u32)>,
+}
+
+/// Build a complete LoRa-APRS packet, ready for `Radio::transmit_start()` in the firmware. `path` may be empty.
+pub fn encode_with(callsign: &str, ssid: u8, path: &str, comment: &str, position: &Position) -> Result<ArrayVec<u8, MAX_PACKET_LEN>, AprsError> {
+    if callsign.is_empty() || callsign.len() > 6 || ssid > 15 { return Err(AprsError::InvalidCallsign) }
+
+    let mut packet = ArrayVec::new();
+    let mut push = |bytes: &[u8]| packet.try_extend_from_slice(bytes).map_err(|_| AprsError::TooLong);
+
+    push(&LORA_APRS_PREFIX)?;
+
+    // Header: CALL-SSID>DEST,PATH:
+    push(callsign.as_bytes())?;
+    if ssid != 0 {
+        push(b"-")?;
+        if ssid >= 10 { push(b"1")? }
+        push(&[b'0' + ssid % 10])?;
+    }
+    push(b">")?;
+    push(DESTINATION.as_bytes())?;
+    if !path.is_empty() {
+        push(b",")?;
+        push(path.as_bytes())?;
+    }
+    push(b":")?;
+
+    // Position without timestamp, no messaging
+    push(b"!")?;
+    push(&compressed_position(position))?;
+
+    // With course/speed in the compressed position, altitude has to go in the comment instead
+    if let (Some(_), Some(altitude_dm)) = (position.course_speed, position.altitude_decimetres) {
+        push(b"/A=")?;
+        push(&altitude_comment_digits(altitude_dm))?;
+    }
+    if !comment.is_empty() {
+        push(b" ")?;
+        push(comment.as_bytes())?;
+    }
+    Ok(packet)
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum AprsError {
+    /// Callsigns are at most 6 characters, SSIDs at most 15.
+    InvalidCallsign,
+    /// The packet wouldn't fit in `MAX_PACKET_LEN`. Shorten the comment.
+    TooLong,
+}
+
+/// The 13 byte compressed position: symbol table, lat, long, symbol code, cs, compression type.
+fn compressed_position(position: &Position) -> [u8; 13] {
+    let lat = position.latitude_microdegrees.clamp(-90_000_000, 90_000_000);
+    let lon = position.longitude_microdegrees.clamp(-180_000_000, 180_000_000);
+    let y = 380_926 * (90_000_000 - lat as i64) as u64 / 1_000_000;
+    let x = 190_463 * (180_000_000 + lon as i64) as u64 / 1_000_000;
+
+    let mut out = [0u8; 13];
+    out[0] = SYMBOL_TABLE;
+    out[1..5].copy_from_slice(&base91(y.min(91u64.pow(4) - 1) as u32));
+    out[5..9].copy_from_slice(&base91(x.min(91u64.pow(4) - 1) as u32));
+    out[9] = SYMBOL_CODE;
+
+    // Compression type byte: current fix, plus which NMEA sentence the cs bytes came from
+    const CURRENT_FIX: u8 = 0b10_0000;
+    const SOURCE_GGA: u8 = 0b1_0000;
+    const SOURCE_RMC: u8 = 0b1_1000;
+    match (position.course_speed, position.altitude_decimetres) {
+        (Some((course, speed)), _) => {
+            let [c, s] = course_speed_cs(course, speed);
+            out[10] = c;
+            out[11] = s;
+            out[12] = CURRENT_FIX | SOURCE_RMC;
+        },
+        (None, Some(altitude_dm)) => {
+            let [c, s] = altitude_cs(altitude_dm);
+            out[10] = c;
+            out[11] = s;
+            out[12] = CURRENT_FIX | SOURCE_GGA;
+        },
+        (None, None) => {
+            // A space means 'no cs data', the rest is ignored
+            out[10] = b' ';
+            out[11] = b' ';
+            out[12] = 0;
+        },
+    }
+    out[12] += 33;
+    out
+}
+
+/// Encode a value as 4 base-91 digits, most significant first.
+fn base91(mut value: u32) -> [u8; 4] {
+    let mut out = [0u8; 4];
+    for digit in out.iter_mut().rev() {
+        *digit = (value % 91) as u8 + 33;
+        value /= 91;
+    }
+    out
+}
+
+/// Altitude in the cs bytes, where altitude = 1.002^cs feet.
+fn altitude_cs(altitude_dm: i32) -> [u8; 2] {
+    let feet = (altitude_dm.max(0) as u32 * 1000 / 3048).max(1);
+    // cs = log2(feet) / log2(1.002), and 1 / log2(1.002) = 346.9237
+    let cs = ((log2_q16(feet) as u64 * 3_469_237 + 10_000 * (1 << 15)) / (10_000 << 16)) as u32;
+    let cs = cs.min(91 * 91 - 1);
+    [(cs / 91) as u8 + 33, (cs % 91) as u8 + 33]
+}
+
+/// Course and speed in the cs bytes. c = course / 4 degrees, speed = 1.08^s - 1 knots.
+fn course_speed_cs(course_tenths_degrees: u16, speed_tenths_knots: u32) -> [u8; 2] {
+    // 0 means 'unknown', due north is sent as 360
+    let course = match (course_tenths_degrees as u32 + 5) / 10 % 360 {
+        0 => 360,
+        degrees => degrees,
+    };
+    let c = (course + 2) / 4;
+
+    // s = log2(speed + 1) / log2(1.08), and 1 / log2(1.08) = 9.00647. Speed is in tenths, so take away log2(10).
+    let log2_speed_plus_1 = log2_q16(speed_tenths_knots.saturating_add(10)) - log2_q16(10);
+    let s = ((log2_speed_plus_1 as u64 * 900_647 + 100_000 * (1 << 15)) / (100_000 << 16)) as u32;
+
+    [c.min(90) as u8 + 33, s.min(90) as u8 + 33]
+}
+
+/// Altitude in feet as the 6 digits following '/A=' in a comment.
+fn altitude_comment_digits(altitude_dm: i32) -> [u8; 6] {
+    let mut feet = (altitude_dm.max(0) as u32 * 1000 / 3048).min(999_999);
+    let mut out = [b'0'; 6];
+    for digit in out.iter_mut().rev() {
+        *digit = b'0' + (feet % 10) as u8;
+        feet /= 10;
+    }
+    out
+}
+
+/// log2(x) as a 16.16 fixed point number. `x` must be at least 1.
+fn log2_q16(x: u32) -> u32 {
+    let x = x.max(1);
+    let integer = 31 - x.leading_zeros();
+
+    // Normalise to [1, 2) in Q30, then find the fractional bits by repeated squaring
+    let mut y = ((x as u64) << 30) >> integer;
+    let mut fraction = 0;
+    for bit in (0..16).rev() {
+        y = (y * y) >> 30;
+        if y >= 2 << 30 {
+            y >>= 1;
+            fraction |= 1 << bit;
+        }
+    }
+    (integer << 16) | fraction
+}
+
+/// Checks against the worked examples in the APRS 1.01 spec. Run with `cargo test`, see lib.rs.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Spec examples: 49 deg 30' N, 72 deg 45' W
+    const POSITION: Position = Position {
+        latitude_microdegrees: 49_500_000,
+        longitude_microdegrees: -72_750_000,
+        altitude_decimetres: None,
+        course_speed: None,
+    };
+
+    #[test]
+    fn compressed_position_matches_spec() {
+        let compressed = compressed_position(&POSITION);
+        assert_eq!(&compressed[1..5], b"5L!!");
+        assert_eq!(&compressed[5..9], b"<*e7");
+    }
+
+    #[test]
+    fn altitude() {
+        // 10004 feet is cs = "S]"
+        assert_eq!(&altitude_cs(30_493), b"S]");
+    }
+
+    #[test]
+    fn course_speed() {
+        // 88 degrees at 36.2 knots is cs = "7P"
+        assert_eq!(&course_speed_cs(880, 362), b"7P");
+    }
+
+    #[test]
+    fn gga_packet() {
+        let position = Position { altitude_decimetres: Some(30_493), ..POSITION };
+        let packet = encode_with("N0CALL", 11, "", "PSat", &position);
+        assert_eq!(packet.as_deref(), Ok(b"<\xFF\x01N0CALL-11>APZPSA:!/5L!!<*e7OS]Q PSat".as_slice()));
+    }
+
+    #[test]
+    fn rmc_packet() {
+        let position = Position { altitude_decimetres: Some(30_493), course_speed: Some((880, 362)), ..POSITION };
+        let packet = encode_with("N0CALL", 0, "WIDE1-1", "", &position);
+        assert_eq!(packet.as_deref(), Ok(b"<\xFF\x01N0CALL>APZPSA,WIDE1-1:!/5L!!<*e7O7PY/A=010004".as_slice()));
+    }
+
+    #[test]
+    fn bad_ssid() {
+        assert_eq!(encode_with("N0CALL", 16, "", "", &POSITION), Err(AprsError::InvalidCallsign));
+    }
+}
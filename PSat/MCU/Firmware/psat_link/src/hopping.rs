@@ -0,0 +1,207 @@
+// Pseudo-random frequency hopping across the 915MHz ISM band.
+//
+// The band is split into `NUM_CHANNELS` channels. Each payload visits every channel exactly once per round, in an order
+// shuffled by a PRNG seeded from its payload ID, and moves to the next channel after every frame it transmits.
+// The ground station knows the payload ID, so it can generate the same sequence. To acquire, it parks on one channel
+// until the payload comes past, after which it knows where the payload is in the sequence and can hop in step with it.
+//
+// FCC 15.247(a)(1)(i) allows a narrowband hopper in 902-928MHz to occupy any one channel for at most 400ms in any 20
+// seconds. A round of the sequence takes `NUM_CHANNELS` beacons, so each channel carries one beacon per round and the
+// limit applies to that beacon's airtime. At 62.5kHz that means SF8 or faster (a beacon takes about 230ms; SF9 takes
+// about 410ms and SF10 740ms), so hopping payloads can't use the slower link profiles or adaptive data rate.
+
+/// Centre frequency of channel 0.
+pub const FIRST_CHANNEL_HZ: u32 = 902_500_000;
+/// Comfortably wider than our 62.5kHz LoRa bandwidth.
+pub const CHANNEL_SPACING_HZ: u32 = 500_000;
+/// Channels span 902.5MHz - 927.0MHz. Some regulators require at least 50 hopping channels.
+pub const NUM_CHANNELS: usize = 50;
+
+/// Longest time we may transmit on one channel, in milliseconds.
+pub const MAX_DWELL_MS: u32 = 400;
+/// The slowest entry in the firmware's `adr::PROFILES` (SF8, 17dBm) whose beacons fit in `MAX_DWELL_MS`.
+pub const SLOWEST_PROFILE: u8 = 4;
+
+/// How many consecutive expected frames the ground station may miss before it assumes it has lost the payload and starts acquisition again.
+pub const MAX_MISSED_HOPS: u8 = 4;
+
+pub fn channel_frequency_hz(channel: u8) -> u32 {
+    FIRST_CHANNEL_HZ + channel as u32 * CHANNEL_SPACING_HZ
+}
+
+/// A small, fast PRNG. Only used to shuffle channels, so quality doesn't matter much, but the
+/// transmitter and ground station must use exactly the same one.
+struct XorShift16(u16);
+impl XorShift16 {
+    fn new(seed: u16) -> Self {
+        // An all-zero state would only ever produce zeroes
+        Self(if seed == 0 { 0xACE1 } else { seed })
+    }
+    fn next(&mut self) -> u16 {
+        self.0 ^= self.0 << 7;
+        self.0 ^= self.0 >> 9;
+        self.0 ^= self.0 << 8;
+        self.0
+    }
+}
+
+/// The hopping schedule for a single payload.
+pub struct HopSequence {
+    channels: [u8; NUM_CHANNELS],
+    index: u8,
+}
+impl HopSequence {
+    /// Generate the hopping schedule used by `payload_id`.
+    pub fn new(payload_id: u8) -> Self {
+        let mut channels = [0u8; NUM_CHANNELS];
+        for (i, ch) in channels.iter_mut().enumerate() {
+            *ch = i as u8;
+        }
+
+        // Fisher-Yates shuffle
+        let mut rng = XorShift16::new(0xACE1 ^ (payload_id as u16).wrapping_mul(0x9E37));
+        for i in (1..NUM_CHANNELS).rev() {
+            let j = rng.next() as usize % (i + 1);
+            channels.swap(i, j);
+        }
+
+        Self { channels, index: 0 }
+    }
+
+    pub fn current_channel(&self) -> u8 {
+        self.channels[self.index as usize]
+    }
+
+    pub fn frequency_hz(&self) -> u32 {
+        channel_frequency_hz(self.current_channel())
+    }
+
+    /// Move to the next channel in the sequence.
+    pub fn advance(&mut self) {
+        self.index = (self.index + 1) % NUM_CHANNELS as u8;
+    }
+}
+
+/// Ground station side: finds a hopping payload and stays on the same channel as it.
+pub struct HopFollower {
+    sequence: HopSequence,
+    state: FollowerState,
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FollowerState {
+    /// Parked on a single channel waiting for the payload to hop onto it.
+    Acquiring,
+    /// Hopping in step with the payload.
+    Tracking { missed_hops: u8 },
+}
+impl HopFollower {
+    pub fn new(payload_id: u8) -> Self {
+        Self { sequence: HopSequence::new(payload_id), state: FollowerState::Acquiring }
+    }
+
+    pub fn state(&self) -> FollowerState {
+        self.state
+    }
+
+    /// The frequency the receiver should be listening on.
+    pub fn frequency_hz(&self) -> u32 {
+        self.sequence.frequency_hz()
+    }
+
+    /// Call when a frame from the payload was heard on `frequency_hz()`. The payload will transmit its next frame on the next channel.
+    pub fn frame_received(&mut self) {
+        self.state = FollowerState::Tracking { missed_hops: 0 };
+        self.sequence.advance();
+    }
+
+    /// Call when the payload was due to transmit but nothing was heard.
+    pub fn hop_missed(&mut self) {
+        match self.state {
+            // Nothing to follow yet, keep waiting on the same channel
+            FollowerState::Acquiring => (),
+            FollowerState::Tracking { missed_hops } if missed_hops >= MAX_MISSED_HOPS => self.state = FollowerState::Acquiring,
+            FollowerState::Tracking { missed_hops } => {
+                // The payload hopped even though we didn't hear it.
+                self.state = FollowerState::Tracking { missed_hops: missed_hops + 1 };
+                self.sequence.advance();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FollowerState, HopFollower, HopSequence, XorShift16, MAX_MISSED_HOPS, NUM_CHANNELS};
+
+    #[test]
+    fn sequence_visits_every_channel_once_per_round() {
+        let mut sequence = HopSequence::new(7);
+        let mut seen = [false; NUM_CHANNELS];
+        for _ in 0..NUM_CHANNELS {
+            let channel = sequence.current_channel() as usize;
+            assert!(!seen[channel], "channel {channel} visited twice");
+            seen[channel] = true;
+            sequence.advance();
+        }
+        assert_eq!(sequence.current_channel(), HopSequence::new(7).current_channel());
+    }
+
+    #[test]
+    fn payloads_have_different_sequences() {
+        assert_ne!(HopSequence::new(1).channels, HopSequence::new(2).channels);
+    }
+
+    #[test]
+    fn ground_station_reacquires_after_losing_the_payload() {
+        let mut ground = HopFollower::new(7);
+        ground.frame_received();
+        for _ in 0..MAX_MISSED_HOPS {
+            ground.hop_missed();
+            assert!(matches!(ground.state(), FollowerState::Tracking { .. }));
+        }
+        ground.hop_missed();
+        assert_eq!(ground.state(), FollowerState::Acquiring);
+    }
+
+    /// A hopping payload and a ground station over a lossy link. Whenever the ground station thinks it's tracking, it
+    /// must be listening on the channel the payload is transmitting on.
+    #[test]
+    fn follower_stays_in_step_over_lossy_link() {
+        const PAYLOAD_ID: u8 = 7;
+        const HOPS: u16 = 2000;
+        const LOSS_PERCENT: u16 = 30;
+
+        let mut payload = HopSequence::new(PAYLOAD_ID);
+        // Start the payload part way through its sequence, as if the ground station was switched on late
+        for _ in 0..17 { payload.advance(); }
+
+        let mut ground = HopFollower::new(PAYLOAD_ID);
+        let mut rng = XorShift16::new(0x1234);
+        let mut acquired_at = None;
+        let mut reacquired = false;
+
+        for hop in 0..HOPS {
+            // Loses frames at random, plus a long outage that should force the ground station to reacquire
+            let outage = (1000..1020).contains(&hop);
+            let lost = outage || (rng.next() % 100) < LOSS_PERCENT;
+
+            let in_step = ground.frequency_hz() == payload.frequency_hz();
+            if let FollowerState::Tracking { .. } = ground.state() {
+                assert!(in_step, "out of step at hop {hop}");
+            }
+
+            if in_step && !lost {
+                reacquired |= hop >= 1020 && acquired_at.is_some();
+                ground.frame_received();
+                acquired_at.get_or_insert(hop);
+            } else {
+                ground.hop_missed();
+            }
+            payload.advance();
+        }
+
+        let acquired_at = acquired_at.expect("never acquired");
+        assert!((acquired_at as usize) < 4 * NUM_CHANNELS, "took {acquired_at} hops to acquire");
+        assert!(reacquired, "never heard the payload after the outage");
+    }
+}
@@ -0,0 +1,11 @@
+// The parts of the radio link that are plain logic, with no registers or peripherals, so they can be tested on a PC.
+// The firmware in ../Rust uses them through a path dependency. Run the tests on the host from this directory:
+//
+//     cargo test
+//
+// Don't add HAL or PAC dependencies here, or this stops building for anything but the MSP430.
+
+#![no_std]
+
+pub mod aprs;
+pub mod hopping;